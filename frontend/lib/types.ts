@@ -25,4 +25,5 @@ export type Table = {
 
 export type Manifest = {
 	tables: Table[];
+	profile: string | null;
 };
@@ -62,6 +62,13 @@ function QueryModalButton({ table }: { table: Table }) {
 	);
 }
 
+// Only the formats exported by the build (listed in `sizes`) get a link
+const DOWNLOAD_FORMATS = [
+	{ ext: "csv", label: "CSV" },
+	{ ext: "tsv", label: "TSV" },
+	{ ext: "parquet", label: "Parquet" },
+];
+
 function downloadUrl(baseUrl: string, table: Table, ext: string) {
 	const collection = table.collection === "layer1" ? "layer1" : "layer2";
 
@@ -159,21 +166,16 @@ export function TableCard({
 				<div className="card-actions flex justify-between items-center">
 					<div>{table.query && <QueryModalButton table={table} />}</div>
 					<div className="flex gap-5">
-						<DownloadLink
-							url={downloadUrl(baseUrl, table, "csv")}
-							filesize={table.sizes.csv}
-							label="CSV"
-						/>
-						<DownloadLink
-							url={downloadUrl(baseUrl, table, "tsv")}
-							filesize={table.sizes.tsv}
-							label="TSV"
-						/>
-						<DownloadLink
-							url={downloadUrl(baseUrl, table, "parquet")}
-							filesize={table.sizes.parquet}
-							label="Parquet"
-						/>
+						{DOWNLOAD_FORMATS.filter(({ ext }) => ext in table.sizes).map(
+							({ ext, label }) => (
+								<DownloadLink
+									key={ext}
+									url={downloadUrl(baseUrl, table, ext)}
+									filesize={table.sizes[ext]}
+									label={label}
+								/>
+							),
+						)}
 					</div>
 				</div>
 			</div>
@@ -0,0 +1,54 @@
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Hex-encoded SHA-256 of the given parts, separated by newlines
+pub fn sha256_hex(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// On-disk pages of a Layer 1 query, kept until the table has been loaded into DuckDB
+pub struct Checkpoint {
+    dir: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new<P: AsRef<Path>>(work_dir: P, endpoint: &str, query: &str) -> anyhow::Result<Self> {
+        let dir = work_dir.as_ref().join(sha256_hex(&[endpoint, query]));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the page with the given page size and offset, or of the whole result if not paginated
+    pub fn page_path(&self, page_size: Option<usize>, offset: usize, extension: &str) -> PathBuf {
+        match page_size {
+            Some(page_size) => self
+                .dir
+                .join(format!("page-{}-{}.{}", page_size, offset, extension)),
+            None => self.dir.join(format!("page-all.{}", extension)),
+        }
+    }
+
+    /// Removes pages left over from an earlier, interrupted run
+    pub fn reset(&self) -> anyhow::Result<()> {
+        self.clear()?;
+        std::fs::create_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        if self.dir.exists() {
+            std::fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+}
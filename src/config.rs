@@ -0,0 +1,216 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    credentials,
+    duckdb_util::escape_sql_literal,
+    export::ExportFormat,
+    sparql_query_metadata::{Pagination, TermDetails},
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "tabulae.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestsConfig {
+    pub retries: Option<usize>,
+    pub retry_delay_ms: Option<u64>,
+    /// A duration such as `30s`
+    pub connect_timeout: Option<String>,
+    pub timeout: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    pub jobs: Option<usize>,
+    pub jobs_per_endpoint: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Layer1Config {
+    /// Pagination for queries without `# Paginate:`, in the same syntax
+    pub paginate: Option<Pagination>,
+    /// A duration such as `30d`
+    pub max_age: Option<String>,
+    /// A duration such as `90d`
+    pub cache_max_age: Option<String>,
+    /// Term details for queries without `# Terms:`
+    pub terms: Option<TermDetails>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    pub formats: Vec<ExportFormat>,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            formats: ExportFormat::ALL.to_vec(),
+        }
+    }
+}
+
+/// `[profiles.<name>]`: a variant of the build against other endpoints and graphs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Defaults to `<dist_dir>-<profile name>`
+    pub dist_dir: Option<PathBuf>,
+    /// Endpoint aliases or URLs mapped to the URLs used in this profile
+    pub endpoints: HashMap<String, String>,
+    /// Graph IRIs in FROM, FROM NAMED, `# DefaultGraph:` and `# NamedGraph:` mapped to the IRIs
+    /// used in this profile
+    pub graphs: HashMap<String, String>,
+}
+
+/// Project settings read from tabulae.toml. Settings also available as command line flags are
+/// merged into `Args`; flags given on the command line take precedence.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub queries_dir: Option<PathBuf>,
+    pub dist_dir: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    pub credentials_file: Option<PathBuf>,
+    /// Names usable in `# Endpoint:` instead of the URL
+    pub endpoints: HashMap<String, String>,
+    pub requests: RequestsConfig,
+    pub concurrency: ConcurrencyConfig,
+    pub layer1: Layer1Config,
+    pub export: ExportConfig,
+    /// DuckDB settings applied with `SET` to every connection, such as `memory_limit = "8GB"`
+    pub duckdb: BTreeMap<String, toml::Value>,
+    pub profiles: HashMap<String, Profile>,
+    /// The profile selected with `--profile`
+    #[serde(skip)]
+    pub profile: Option<(String, Profile)>,
+}
+
+impl Config {
+    /// Reads the config file; a missing file is only an error if `required`
+    pub fn load<P: AsRef<Path>>(path: P, required: bool) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !required && !path.exists() {
+            return Ok(Config::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let mut config: Config = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+
+        // Paths are relative to the directory of the config file
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        for dir in [
+            &mut config.queries_dir,
+            &mut config.dist_dir,
+            &mut config.state_dir,
+            &mut config.credentials_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *dir = base_dir.join(&*dir);
+        }
+        for profile in config.profiles.values_mut() {
+            if let Some(dist_dir) = &mut profile.dist_dir {
+                *dist_dir = base_dir.join(&*dist_dir);
+            }
+        }
+        let profile_endpoints = config
+            .profiles
+            .values()
+            .flat_map(|profile| profile.endpoints.values());
+        for url in config.endpoints.values().chain(profile_endpoints) {
+            credentials::reject_credentials_in_url(url)?;
+        }
+        for name in config.duckdb.keys() {
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(anyhow::anyhow!("Invalid DuckDB setting name: {}", name));
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn select_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown profile: {}", name))?;
+        self.profile = Some((name.to_string(), profile.clone()));
+        Ok(())
+    }
+
+    pub fn profile_name(&self) -> Option<&str> {
+        self.profile.as_ref().map(|(name, _)| name.as_str())
+    }
+
+    /// The URL of an endpoint alias, or the endpoint itself if it is not an alias, as remapped
+    /// by the active profile
+    pub fn resolve_endpoint<'a>(&'a self, endpoint: &'a str) -> &'a str {
+        let profile_endpoints = self.profile.as_ref().map(|(_, profile)| &profile.endpoints);
+        if let Some(url) = profile_endpoints.and_then(|endpoints| endpoints.get(endpoint)) {
+            return url;
+        }
+        let url = self
+            .endpoints
+            .get(endpoint)
+            .map(|url| url.as_str())
+            .unwrap_or(endpoint);
+        profile_endpoints
+            .and_then(|endpoints| endpoints.get(url))
+            .map(|url| url.as_str())
+            .unwrap_or(url)
+    }
+
+    /// A graph IRI as remapped by the active profile
+    pub fn resolve_graph(&self, graph: &str) -> String {
+        self.profile
+            .as_ref()
+            .and_then(|(_, profile)| profile.graphs.get(graph))
+            .map(|graph| graph.to_string())
+            .unwrap_or_else(|| graph.to_string())
+    }
+
+    /// Replaces the graph IRIs of FROM and FROM NAMED clauses as remapped by the active profile
+    pub fn remap_from_graphs(&self, query: &str) -> String {
+        let Some((_, profile)) = &self.profile else {
+            return query.to_string();
+        };
+        if profile.graphs.is_empty() {
+            return query.to_string();
+        }
+        let from = regex::Regex::new(r"(?i)(\bFROM\s+(?:NAMED\s+)?<)([^>]*)>").unwrap();
+        from.replace_all(query, |caps: &regex::Captures| {
+            format!("{}{}>", &caps[1], self.resolve_graph(&caps[2]))
+        })
+        .into_owned()
+    }
+
+    pub fn apply_duckdb_settings(&self, conn: &duckdb::Connection) -> anyhow::Result<()> {
+        for (name, value) in &self.duckdb {
+            let value = match value {
+                toml::Value::String(value) => escape_sql_literal(value),
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "DuckDB setting {} must be a string, number or boolean",
+                        name
+                    ));
+                }
+            };
+            conn.execute_batch(&format!("SET {} = {}", name, value))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+use std::{fmt, path::Path};
+
+use serde::{Deserialize, Deserializer};
+
+/// A secret read from the credentials file or the environment, kept out of logs
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    /// `env:NAME` reads the secret from the environment variable `NAME`
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        match value.strip_prefix("env:") {
+            Some(name) => std::env::var(name).map(Secret).map_err(|_| {
+                serde::de::Error::custom(format!("Environment variable {} is not set", name))
+            }),
+            None => Ok(Secret(value)),
+        }
+    }
+}
+
+/// How requests to an endpoint are authenticated
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Credential {
+    Basic {
+        username: String,
+        password: Secret,
+    },
+    Bearer {
+        token: Secret,
+    },
+    /// An arbitrary header, such as `X-API-Key`
+    Header {
+        name: String,
+        value: Secret,
+    },
+}
+
+impl Credential {
+    pub fn apply(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::RequestBuilder> {
+        Ok(match self {
+            Credential::Basic { username, password } => {
+                request.basic_auth(username, Some(password.expose()))
+            }
+            Credential::Bearer { token } => request.bearer_auth(token.expose()),
+            Credential::Header { name, value } => {
+                // The error would show the value, so it is replaced
+                let mut value = reqwest::header::HeaderValue::from_str(value.expose())
+                    .map_err(|_| anyhow::anyhow!("Invalid value for header {}", name))?;
+                value.set_sensitive(true);
+                request.header(name, value)
+            }
+        })
+    }
+}
+
+/// Credentials per endpoint, loaded from a JSON file mapping endpoint URLs (or their prefixes)
+/// to credentials
+#[derive(Debug, Default)]
+pub struct Credentials {
+    endpoints: Vec<(reqwest::Url, Credential)>,
+}
+
+impl Credentials {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).map_err(|e| {
+            anyhow::anyhow!("Failed to open credentials file {}: {}", path.display(), e)
+        })?;
+        let endpoints: std::collections::HashMap<String, Credential> =
+            serde_json::from_reader(std::io::BufReader::new(file)).map_err(|e| {
+                anyhow::anyhow!("Failed to read credentials file {}: {}", path.display(), e)
+            })?;
+
+        let endpoints = endpoints
+            .into_iter()
+            .map(|(prefix, credential)| {
+                let url = reqwest::Url::parse(&prefix)
+                    .map_err(|e| anyhow::anyhow!("Invalid endpoint URL {}: {}", prefix, e))?;
+                if url.query().is_some() || url.fragment().is_some() {
+                    return Err(anyhow::anyhow!(
+                        "Endpoint URL {} must not have a query or fragment",
+                        prefix
+                    ));
+                }
+                Ok((url, credential))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self { endpoints })
+    }
+
+    /// The credential with the longest URL prefix matching the endpoint. The scheme, host and
+    /// port must be the same, and the path must match up to a `/`, so that a credential is
+    /// never sent to another server.
+    pub fn for_endpoint(&self, endpoint: &str) -> Option<&Credential> {
+        let endpoint = reqwest::Url::parse(endpoint).ok()?;
+        self.endpoints
+            .iter()
+            .filter(|(prefix, _)| {
+                same_origin(prefix, &endpoint) && path_has_prefix(endpoint.path(), prefix.path())
+            })
+            .max_by_key(|(prefix, _)| prefix.path().len())
+            .map(|(_, credential)| credential)
+    }
+}
+
+fn same_origin(a: &reqwest::Url, b: &reqwest::Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host() == b.host()
+        && a.port_or_known_default() == b.port_or_known_default()
+}
+
+/// Redirect policy for requests that carry a credential. reqwest strips only `Authorization`
+/// and cookies on a redirect to another host, and keeps them on a downgrade to `http`, so any
+/// redirect that changes the scheme, host or port is refused.
+pub fn redirect_policy() -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(|attempt| {
+        let Some(origin) = attempt.previous().first() else {
+            return attempt.follow();
+        };
+        if !same_origin(origin, attempt.url()) {
+            let error = format!(
+                "Refusing to send credentials for {} to {}",
+                origin,
+                attempt.url()
+            );
+            attempt.error(error)
+        } else if attempt.previous().len() > 10 {
+            attempt.error("Too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// The query text and tabulae.toml may be shared, so endpoint URLs must not carry secrets
+pub fn reject_credentials_in_url(endpoint: &str) -> anyhow::Result<()> {
+    if let Ok(url) = reqwest::Url::parse(endpoint)
+        && (!url.username().is_empty() || url.password().is_some())
+    {
+        return Err(anyhow::anyhow!(
+            "Credentials must not be written in the endpoint URL; use --credentials-file"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(prefixes: &[&str]) -> Credentials {
+        let endpoints = prefixes
+            .iter()
+            .map(|prefix| {
+                (
+                    reqwest::Url::parse(prefix).unwrap(),
+                    Credential::Bearer {
+                        token: Secret(prefix.to_string()),
+                    },
+                )
+            })
+            .collect();
+        Credentials { endpoints }
+    }
+
+    fn matched<'a>(credentials: &'a Credentials, endpoint: &str) -> Option<&'a str> {
+        match credentials.for_endpoint(endpoint)? {
+            Credential::Bearer { token } => Some(token.expose()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn matches_same_host_only() {
+        let credentials = credentials(&["https://internal.example.org"]);
+        assert_eq!(
+            matched(&credentials, "https://internal.example.org/sparql"),
+            Some("https://internal.example.org")
+        );
+        assert_eq!(
+            matched(
+                &credentials,
+                "https://internal.example.org.attacker.net/sparql"
+            ),
+            None
+        );
+        assert_eq!(
+            matched(&credentials, "http://internal.example.org/sparql"),
+            None
+        );
+        assert_eq!(
+            matched(&credentials, "https://internal.example.org:8443/sparql"),
+            None
+        );
+        assert_eq!(
+            matched(&credentials, "https://internal.example.org:443/sparql"),
+            Some("https://internal.example.org")
+        );
+    }
+
+    #[test]
+    fn matches_path_on_segment_boundary() {
+        let credentials = credentials(&["https://example.org/sparql", "https://example.org/"]);
+        assert_eq!(
+            matched(&credentials, "https://example.org/sparql"),
+            Some("https://example.org/sparql")
+        );
+        assert_eq!(
+            matched(&credentials, "https://example.org/sparql/private"),
+            Some("https://example.org/sparql")
+        );
+        assert_eq!(
+            matched(&credentials, "https://example.org/sparql-public"),
+            Some("https://example.org/")
+        );
+    }
+
+    /// Accepts one connection and answers it with `response`, returning the request head
+    fn serve_once(
+        listener: std::net::TcpListener,
+        response: String,
+    ) -> std::thread::JoinHandle<String> {
+        use std::io::{BufRead, Write};
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(&stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            (&stream).write_all(response.as_bytes()).unwrap();
+            head
+        })
+    }
+
+    #[tokio::test]
+    async fn header_credential_is_not_sent_to_another_origin() {
+        let target = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_url = format!("http://{}/sparql", target.local_addr().unwrap());
+        target.set_nonblocking(true).unwrap();
+
+        let endpoint = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint_url = format!("http://{}/sparql", endpoint.local_addr().unwrap());
+        let endpoint = serve_once(
+            endpoint,
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                target_url
+            ),
+        );
+
+        let credential = Credential::Header {
+            name: "X-API-Key".to_string(),
+            value: Secret("secret".to_string()),
+        };
+        let client = reqwest::Client::builder()
+            .redirect(redirect_policy())
+            .no_proxy()
+            .build()
+            .unwrap();
+        let request = credential.apply(client.get(&endpoint_url)).unwrap();
+        let error = request.send().await.unwrap_err();
+        assert!(error.is_redirect(), "{:?}", error);
+
+        let head = endpoint.join().unwrap().to_lowercase();
+        assert!(head.contains("x-api-key: secret"), "{}", head);
+        assert_eq!(
+            target.accept().unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn same_origin_compares_scheme_host_and_port() {
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+        assert!(same_origin(
+            &url("https://example.org/sparql"),
+            &url("https://example.org:443/other")
+        ));
+        assert!(!same_origin(
+            &url("https://example.org/sparql"),
+            &url("http://example.org/sparql")
+        ));
+        assert!(!same_origin(
+            &url("https://example.org/sparql"),
+            &url("https://example.org:8443/sparql")
+        ));
+        assert!(!same_origin(
+            &url("https://example.org/sparql"),
+            &url("https://mirror.example.org/sparql")
+        ));
+    }
+}
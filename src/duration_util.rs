@@ -0,0 +1,23 @@
+use std::time::Duration;
+
+/// Parses durations such as `500ms`, `30s`, `5m`, `2h` or `1d`; a bare number is in seconds
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid duration: {}", s))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 60.0 * 60.0,
+        "d" => number * 24.0 * 60.0 * 60.0,
+        _ => return Err(anyhow::anyhow!("Unknown unit in duration: {}", s)),
+    };
+
+    Ok(Duration::try_from_secs_f64(seconds)?)
+}
@@ -2,30 +2,75 @@ use std::path::Path;
 
 use crate::duckdb_util::escape_sql_identifier;
 
+/// File formats tables are exported to, selected by `[export] formats` in tabulae.toml
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Tsv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] =
+        [ExportFormat::Csv, ExportFormat::Tsv, ExportFormat::Parquet];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Tsv => "tsv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+
+    fn copy_options(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "FORMAT CSV",
+            ExportFormat::Tsv => "FORMAT CSV, DELIMITER '\t'",
+            ExportFormat::Parquet => "FORMAT parquet",
+        }
+    }
+}
+
+/// Whether the exported files of the relation are exactly those of `formats`
+pub fn is_exported<P: AsRef<Path>>(dest_dir: P, relation: &str, formats: &[ExportFormat]) -> bool {
+    ExportFormat::ALL.iter().all(|format| {
+        let dest_path = dest_dir
+            .as_ref()
+            .join(format!("{}.{}", relation, format.extension()));
+        dest_path.exists() == formats.contains(format)
+    })
+}
+
 pub fn export_relation_to_files<P: AsRef<Path>>(
     conn: &duckdb::Connection,
     database_name: &str,
     relation: &str,
     dest_dir: P,
+    formats: &[ExportFormat],
 ) -> anyhow::Result<()> {
-    let export_types = &[
-        ("csv", "FORMAT CSV"),
-        ("tsv", "FORMAT CSV, DELIMITER '\t'"),
-        ("parquet", "FORMAT parquet"),
-    ];
-    for &(ext, format) in export_types {
-        let dest_path = dest_dir.as_ref().join(format!("{}.{}", relation, ext));
+    // Files of formats no longer exported would otherwise be left stale
+    for format in ExportFormat::ALL {
+        let dest_path = dest_dir
+            .as_ref()
+            .join(format!("{}.{}", relation, format.extension()));
 
         if std::path::Path::new(&dest_path).exists() {
             std::fs::remove_file(&dest_path)?;
         }
+    }
+
+    for format in formats {
+        let dest_path = dest_dir
+            .as_ref()
+            .join(format!("{}.{}", relation, format.extension()));
 
         let sql = format!(
             "COPY (FROM {}.{}) TO {} ({})",
             escape_sql_identifier(database_name),
             escape_sql_identifier(relation),
             escape_sql_identifier(&dest_path.to_string_lossy()),
-            format
+            format.copy_options()
         );
         conn.execute_batch(&sql)?;
     }
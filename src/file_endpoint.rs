@@ -0,0 +1,209 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    sparql_query_modifier,
+    sparql_results::{Binding, BindingHandler, ResultFormat, read_sparql_results_file},
+};
+
+/// The file or directory of a `file://` endpoint; relative paths are relative to the current
+/// directory, e.g. `file://fixtures/clinvar.srj`
+pub fn path_of(endpoint: &str) -> Option<PathBuf> {
+    endpoint.strip_prefix("file://").map(PathBuf::from)
+}
+
+/// The recorded result files of a `file://` endpoint: the file itself, or the files of a
+/// directory in name order
+pub fn source_files(source: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !source.is_dir() {
+        return Ok(vec![source.to_path_buf()]);
+    }
+    let mut sources = std::fs::read_dir(source)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    sources.retain(|path| path.is_file());
+    sources.sort();
+    Ok(sources)
+}
+
+/// Writes the bindings within the OFFSET/LIMIT of the query as a SPARQL JSON document
+struct SliceWriter<W: Write> {
+    writer: W,
+    skip: usize,
+    remaining: Option<usize>,
+    head_written: bool,
+    written: usize,
+}
+
+impl<W: Write> SliceWriter<W> {
+    fn write_head(&mut self, vars: &[String]) -> anyhow::Result<()> {
+        write!(
+            self.writer,
+            r#"{{"head":{{"vars":{}}},"results":{{"bindings":["#,
+            serde_json::to_string(vars)?
+        )?;
+        self.head_written = true;
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<usize> {
+        if !self.head_written {
+            self.write_head(&[])?;
+        }
+        write!(self.writer, "]}}}}")?;
+        self.writer.flush()?;
+        Ok(self.written)
+    }
+}
+
+impl<W: Write> BindingHandler for SliceWriter<W> {
+    fn head(&mut self, vars: &[String]) -> anyhow::Result<()> {
+        // Pages recorded from the same query share their variables, so the first head is used
+        if !self.head_written {
+            self.write_head(vars)?;
+        }
+        Ok(())
+    }
+
+    fn binding(&mut self, binding: Binding) -> anyhow::Result<()> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return Ok(());
+        }
+        if self.remaining == Some(0) {
+            return Ok(());
+        }
+        if self.written > 0 {
+            write!(self.writer, ",")?;
+        }
+        serde_json::to_writer(&mut self.writer, &binding)?;
+        self.written += 1;
+        self.remaining = self.remaining.map(|remaining| remaining - 1);
+        Ok(())
+    }
+}
+
+/// Serves a query from recorded SPARQL JSON results, as if an endpoint had answered it. A
+/// directory is read as the concatenation of its files in name order. Only the trailing
+/// LIMIT/OFFSET of the query is applied; the rest of the query is ignored.
+pub fn save_sparql_result_to_file<P: AsRef<Path>>(
+    source: &Path,
+    query: &str,
+    format: ResultFormat,
+    file: P,
+) -> anyhow::Result<usize> {
+    if format != ResultFormat::Json {
+        return Err(anyhow::anyhow!(
+            "file:// endpoints serve SPARQL JSON results, but {:?} was requested",
+            format
+        ));
+    }
+
+    let sources = source_files(source)?;
+    let (offset, limit) = sparql_query_modifier::trailing_slice(query);
+    let mut writer = SliceWriter {
+        writer: std::io::BufWriter::new(std::fs::File::create(file)?),
+        skip: offset,
+        remaining: limit,
+        head_written: false,
+        written: 0,
+    };
+    for source in sources {
+        read_sparql_results_file(&source, ResultFormat::Json, &mut writer)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// The variables and the `?id` of each binding of a served result
+    #[derive(Default)]
+    struct Ids {
+        vars: Vec<String>,
+        ids: Vec<String>,
+    }
+
+    impl BindingHandler for Ids {
+        fn head(&mut self, vars: &[String]) -> anyhow::Result<()> {
+            self.vars = vars.to_vec();
+            Ok(())
+        }
+
+        fn binding(&mut self, binding: Binding) -> anyhow::Result<()> {
+            self.ids.push(binding.values["id"].value.clone());
+            Ok(())
+        }
+    }
+
+    fn serve(source: &Path, query: &str) -> (usize, Ids) {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let num_bindings =
+            save_sparql_result_to_file(source, query, ResultFormat::Json, file.path()).unwrap();
+        let mut ids = Ids::default();
+        read_sparql_results_file(file.path(), ResultFormat::Json, &mut ids).unwrap();
+        assert_eq!(num_bindings, ids.ids.len());
+        (num_bindings, ids)
+    }
+
+    #[test]
+    fn directory_is_read_in_name_order() {
+        let (_, ids) = serve(&fixture("clinvar"), "SELECT * WHERE { ?clinvar ?p ?id }");
+        assert_eq!(ids.vars, ["clinvar", "id"]);
+        assert_eq!(ids.ids, ["1", "2", "3", "4", "5"]);
+
+        let (_, ids) = serve(
+            &fixture("clinvar/page-2.srj"),
+            "SELECT * WHERE { ?s ?p ?o }",
+        );
+        assert_eq!(ids.ids, ["4", "5"]);
+    }
+
+    #[test]
+    fn slice_spans_files() {
+        let query = "SELECT * WHERE { ?clinvar ?p ?id }\nOFFSET 2\nLIMIT 2\n# Paginate: 2\n";
+        let (_, ids) = serve(&fixture("clinvar"), query);
+        assert_eq!(ids.ids, ["3", "4"]);
+
+        let query = "SELECT * WHERE { ?clinvar ?p ?id } LIMIT 2 OFFSET 4";
+        let (_, ids) = serve(&fixture("clinvar"), query);
+        assert_eq!(ids.ids, ["5"]);
+    }
+
+    #[test]
+    fn slice_past_the_end_is_empty() {
+        let query = "SELECT * WHERE { ?clinvar ?p ?id } LIMIT 2 OFFSET 10";
+        let (num_bindings, ids) = serve(&fixture("clinvar"), query);
+        assert_eq!(num_bindings, 0);
+        assert_eq!(ids.vars, ["clinvar", "id"]);
+
+        let query = "SELECT * WHERE { ?clinvar ?p ?id } LIMIT 0";
+        let (num_bindings, _) = serve(&fixture("clinvar"), query);
+        assert_eq!(num_bindings, 0);
+    }
+
+    #[test]
+    fn only_json_is_served() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        for format in [ResultFormat::Xml, ResultFormat::Tsv, ResultFormat::Csv] {
+            assert!(
+                save_sparql_result_to_file(
+                    &fixture("clinvar"),
+                    "SELECT * WHERE { ?s ?p ?o }",
+                    format,
+                    file.path()
+                )
+                .is_err()
+            );
+        }
+    }
+}
@@ -1,62 +1,402 @@
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
 
+use duckdb::params;
 use glob::glob;
+use indicatif::MultiProgress;
+use spargebra::SparqlParser;
 use tempfile::NamedTempFile;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
 
 use crate::{
     Args,
+    checkpoint::{Checkpoint, sha256_hex},
+    credentials::Credentials,
     duckdb_util::{escape_sql_identifier, escape_sql_literal},
-    export, sparql_client, sparql_query_metadata, sparql_query_modifier, sparql_result_to_duckdb,
+    export::{self, ExportFormat},
+    file_endpoint, local_source,
+    response_cache::ResponseCache,
+    sparql_client::{self, RequestOptions, SparqlClient},
+    sparql_query_metadata::{
+        self, Pagination, PaginationMode, QueryMetadata, ValuesAs, ValuesFrom,
+    },
+    sparql_query_modifier, sparql_result_to_duckdb,
+    sparql_results::{self, LastBinding, ResultFormat, Value},
     used_queries::{self, ensure_metadata_schema},
 };
 
+/// Fetches a page into `path` unless a completed page from an earlier run is already there
+async fn fetch_page(
+    client: &SparqlClient,
+    log_target: &str,
+    query: &str,
+    options: &RequestOptions,
+    path: &Path,
+) -> anyhow::Result<usize> {
+    if path.exists() {
+        let num_bindings = sparql_client::count_bindings(path, options.format)?;
+        client.ensure_cached(query, options, path)?;
+        log::info!(
+            target: log_target,
+            "Reusing {} binding(s) from checkpoint {}",
+            num_bindings,
+            path.display()
+        );
+        return Ok(num_bindings);
+    }
+
+    // Download next to the destination and rename it only once the page is complete
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory"))?;
+    let temp_file = NamedTempFile::new_in(dir)?;
+    let num_bindings = client
+        .save_sparql_result_to_file(log_target, query, options, &temp_file)
+        .await?;
+    temp_file.persist(path)?;
+
+    Ok(num_bindings)
+}
+
 async fn request_single(
+    client: &SparqlClient,
     log_target: &str,
     query: &str,
-    endpoint: &str,
-) -> anyhow::Result<Vec<NamedTempFile>> {
-    let temp = tempfile::NamedTempFile::new()?;
-    let num_bindings = sparql_client::save_sparql_result_to_file(query, endpoint, &temp).await?;
+    options: &RequestOptions,
+    checkpoint: &Checkpoint,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let path = checkpoint.page_path(None, 0, options.format.extension());
+    let num_bindings = fetch_page(client, log_target, query, options, &path).await?;
     log::info!(target: log_target, "{} binding(s) received", num_bindings);
 
-    Ok(vec![temp])
+    Ok(vec![path])
+}
+
+/// Values of the pagination keys in the last binding of a page
+fn last_key_values(
+    path: &Path,
+    format: ResultFormat,
+    keys: &[String],
+) -> anyhow::Result<Vec<Value>> {
+    let mut last = LastBinding::default();
+    sparql_results::read_sparql_results_file(path, format, &mut last)?;
+    let mut binding = last
+        .0
+        .ok_or_else(|| anyhow::anyhow!("No bindings in {}", path.display()))?;
+    keys.iter()
+        .map(|key| {
+            binding.values.remove(key).ok_or_else(|| {
+                anyhow::anyhow!("Pagination key ?{} is unbound in the last binding", key)
+            })
+        })
+        .collect()
 }
 
 async fn request_with_pagination(
+    client: &SparqlClient,
     log_target: &str,
     query: &str,
-    endpoint: &str,
-    paginate: usize,
-) -> anyhow::Result<Vec<NamedTempFile>> {
-    log::info!(target: log_target, "Pagination enabled with limit {}", paginate);
+    options: &RequestOptions,
+    pagination: &Pagination,
+    checkpoint: &Checkpoint,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let paginate = pagination.limit;
+    let mut textual = false;
+    if pagination.keys.is_empty() {
+        log::info!(target: log_target, "Pagination enabled with limit {}", paginate);
+        textual = match pagination.mode {
+            PaginationMode::Text => true,
+            PaginationMode::Parse => false,
+            PaginationMode::Auto => match SparqlParser::new().parse_query(query) {
+                Ok(_) => false,
+                Err(e) => {
+                    log::warn!(
+                        target: log_target,
+                        "Failed to parse the query ({}); setting LIMIT/OFFSET textually",
+                        e
+                    );
+                    true
+                }
+            },
+        };
+        let has_order_by = if textual {
+            sparql_query_modifier::has_order_by_text(query)
+        } else {
+            sparql_query_modifier::has_order_by(query)?
+        };
+        if !has_order_by {
+            log::warn!(
+                target: log_target,
+                "The query has no ORDER BY; pages may overlap or miss solutions"
+            );
+        }
+    } else {
+        if options.format == ResultFormat::Csv {
+            return Err(anyhow::anyhow!(
+                "Keyset pagination needs typed values, which CSV results don't have"
+            ));
+        }
+        log::info!(
+            target: log_target,
+            "Keyset pagination enabled with limit {} by ?{}",
+            paginate,
+            pagination.keys.join(" ?")
+        );
+    }
 
     let mut paths = vec![];
     let mut offset = 0;
+    let mut after: Option<Vec<Value>> = None;
     loop {
-        let query = sparql_query_modifier::rewrite_query_limit_offset(query, paginate, offset)?;
-        let query = query.to_string();
+        let query = if textual {
+            sparql_query_modifier::paginate_query_text(query, paginate, offset)
+        } else if pagination.keys.is_empty() {
+            sparql_query_modifier::rewrite_query_limit_offset(query, paginate, offset)?.to_string()
+        } else {
+            sparql_query_modifier::rewrite_query_keyset(
+                query,
+                paginate,
+                &pagination.keys,
+                after.as_deref(),
+            )?
+            .to_string()
+        };
 
-        let temp_file: NamedTempFile = NamedTempFile::new()?;
-        let num_bindings =
-            sparql_client::save_sparql_result_to_file(&query, endpoint, &temp_file).await?;
-        paths.push(temp_file);
+        let path = checkpoint.page_path(Some(paginate), offset, options.format.extension());
+        // Each page is retried on its own so that a flaky page doesn't discard the others
+        let num_bindings = fetch_page(client, log_target, &query, options, &path)
+            .await
+            .map_err(|e| e.context(format!("Failed to fetch page at offset {}", offset)))?;
         if num_bindings < paginate {
+            paths.push(path);
             log::info!(target: log_target, "{} binding(s) received in total", offset + num_bindings);
             break;
         }
+        if !pagination.keys.is_empty() {
+            after = Some(last_key_values(&path, options.format, &pagination.keys)?);
+        }
+        paths.push(path);
         offset += num_bindings;
     }
 
     Ok(paths)
 }
-async fn process_query<P: AsRef<Path>>(
+
+/// Limits the number of requests in flight against each endpoint
+struct EndpointLimiter {
+    permits_per_endpoint: usize,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl EndpointLimiter {
+    fn new(permits_per_endpoint: usize) -> Self {
+        Self {
+            permits_per_endpoint,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn acquire(&self, endpoint: &str) -> anyhow::Result<OwnedSemaphorePermit> {
+        let semaphore = self
+            .semaphores
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Endpoint limiter lock poisoned"))?
+            .entry(endpoint.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.permits_per_endpoint)))
+            .clone();
+        Ok(semaphore.acquire_owned().await?)
+    }
+}
+
+/// State shared by the concurrently running Layer 1 queries
+struct Context {
+    args: Args,
+    resume: bool,
+    client: SparqlClient,
+    progress: MultiProgress,
+    jobs: Arc<Semaphore>,
+    endpoints: EndpointLimiter,
+    credentials: Credentials,
+    conn: Mutex<duckdb::Connection>,
+}
+
+impl Context {
+    fn conn(&self) -> anyhow::Result<MutexGuard<'_, duckdb::Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow::anyhow!("DuckDB connection lock poisoned"))
+    }
+}
+
+/// Distinct values of a column of an already built Layer 1 table, as SPARQL terms
+fn distinct_terms(
     conn: &duckdb::Connection,
-    query_path: P,
-    args: &Args,
-    force: bool,
-) -> anyhow::Result<()> {
-    let name = query_path
+    values_from: &ValuesFrom,
+) -> anyhow::Result<Vec<String>> {
+    let data_type: String = conn
+        .query_row(
+            "SELECT data_type FROM duckdb_columns() WHERE table_name = ? AND column_name = ?",
+            params![values_from.table, values_from.column],
+            |row| row.get(0),
+        )
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Column {} not found in {}",
+                values_from.column,
+                values_from.table
+            )
+        })?;
+    let mut column = escape_sql_identifier(&values_from.column);
+    // The kind of each value is known only if the table keeps term details with `# Terms`
+    let kind_column = format!("{}__kind", values_from.column);
+    let mut kind = "NULL".to_string();
+    if data_type.starts_with("STRUCT(") {
+        kind = format!("{}.kind", column);
+        column = format!("{}.value", column);
+    } else if conn.query_row(
+        "SELECT count(*) FROM duckdb_columns() WHERE table_name = ? AND column_name = ?",
+        params![values_from.table, kind_column],
+        |row| row.get::<_, i64>(0),
+    )? > 0
+    {
+        kind = escape_sql_identifier(&kind_column);
+    }
+    let mut stmt = conn.prepare(&format!(
+        "SELECT DISTINCT CAST({} AS VARCHAR) AS value, typeof({}) AS type, {} AS kind FROM {} WHERE {} IS NOT NULL ORDER BY value",
+        column,
+        column,
+        kind,
+        escape_sql_identifier(&values_from.table),
+        column
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        let is_iri = match values_from.values_as {
+            Some(ValuesAs::Iri) => true,
+            Some(ValuesAs::Literal) => false,
+            None => row.get::<_, Option<String>>(2)?.as_deref() == Some("uri"),
+        };
+        Ok(sparql_query_modifier::sparql_term_from_sql(
+            &row.get::<_, String>(0)?,
+            &row.get::<_, String>(1)?,
+            is_iri,
+        ))
+    })?;
+    let terms = rows.collect::<Result<Vec<String>, _>>()?;
+
+    Ok(terms)
+}
+
+fn now_us() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as i64
+}
+
+/// Size and modification time of each local file a table is read from, so that a replaced dump
+/// or recording makes the table stale
+fn local_file_inputs(endpoint: &str) -> anyhow::Result<Vec<String>> {
+    let files = if let Some(path) = file_endpoint::path_of(endpoint) {
+        file_endpoint::source_files(&path)?
+    } else if let Some(source) = local_source::source_of(endpoint) {
+        local_source::source_files(source)?
+    } else {
+        return Ok(vec![]);
+    };
+    files
+        .iter()
+        .map(|file| {
+            let metadata = std::fs::metadata(file)?;
+            let mtime_us = metadata
+                .modified()?
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+                .as_micros();
+            Ok(format!(
+                "file={}:{}:{}",
+                file.display(),
+                metadata.len(),
+                mtime_us
+            ))
+        })
+        .collect()
+}
+
+/// Hash of what determines the contents of a table: the query text, with line endings and
+/// trailing whitespace normalized, the metadata in effect, including settings from the config
+/// file, and a description of each input outside the query, such as the table values are read
+/// from
+fn content_hash(query: &str, qm: &QueryMetadata, inputs: &[String]) -> String {
+    let normalized_query = query
+        .lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n");
+    let pagination = qm
+        .paginate
         .as_ref()
+        .map(|pagination| {
+            format!(
+                "limit={};keys={};mode={}",
+                pagination.limit,
+                pagination.keys.join(" "),
+                pagination.mode.as_str()
+            )
+        })
+        .unwrap_or_default();
+    let parameters = qm
+        .parameters
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<String>>();
+    let format = format!("format={}", qm.format.extension());
+    let method = format!("method={}", qm.method.as_str());
+    let default_graphs = qm.default_graphs.join(" ");
+    let named_graphs = qm.named_graphs.join(" ");
+    let parameters = parameters.join("&");
+    let mut parts = vec![
+        normalized_query.as_str(),
+        &qm.endpoint,
+        &pagination,
+        &format,
+        &method,
+        &default_graphs,
+        &named_graphs,
+        &parameters,
+    ];
+
+    // These shape the table rather than the request, and may come from tabulae.toml
+    let terms = qm
+        .terms
+        .map(|terms| format!("terms={}", terms.as_str()))
+        .unwrap_or_default();
+    let column_types = qm
+        .column_types
+        .iter()
+        .map(|column_type| format!("type={}:{}", column_type.variable, column_type.sql_type))
+        .collect::<Vec<String>>();
+    let language_pivots = qm
+        .language_pivots
+        .iter()
+        .map(|pivot| format!("pivot={}:{}", pivot.variable, pivot.languages.join(" ")))
+        .collect::<Vec<String>>();
+    parts.push(&terms);
+    parts.extend(column_types.iter().map(String::as_str));
+    parts.extend(language_pivots.iter().map(String::as_str));
+    parts.extend(inputs.iter().map(String::as_str));
+
+    sha256_hex(&parts)
+}
+
+/// Builds a Layer 1 table unless it is up to date
+async fn process_query(ctx: Arc<Context>, query_path: PathBuf, force: bool) -> anyhow::Result<()> {
+    let name = query_path
         .file_stem()
         .ok_or_else(|| anyhow::anyhow!("Failed to get file stem"))?
         .to_str()
@@ -65,45 +405,224 @@ async fn process_query<P: AsRef<Path>>(
 
     log::info!(target: &log_target, "Start processing");
 
-    // Check if the query needs to be updated
     let query_mtime_us = std::fs::metadata(&query_path)?
         .modified()?
         .duration_since(std::time::SystemTime::UNIX_EPOCH)?
         .as_micros() as i64;
-    if !force {
-        let stored_mtime_us = used_queries::get_mtime(conn, name)?;
+    let query = ctx
+        .args
+        .config
+        .remap_from_graphs(&std::fs::read_to_string(&query_path)?);
+    let qm = sparql_query_metadata::extract_query_metadata(&query, &ctx.args.config)?;
+    // Only SELECT results can be loaded as tables; queries that fail to parse are left to the
+    // endpoint, as they may use vendor extensions
+    if let Ok(parsed) = SparqlParser::new().parse_query(&query)
+        && !matches!(parsed, spargebra::Query::Select { .. })
+    {
+        return Err(anyhow::anyhow!(
+            "Layer 1 queries must be SELECT queries; CONSTRUCT, DESCRIBE and ASK results cannot be loaded as tables"
+        ));
+    }
 
-        if let Some(stored_mtime) = stored_mtime_us
-            && stored_mtime >= query_mtime_us
-        {
+    // Check if the query needs to be updated
+    let mut inputs = local_file_inputs(&qm.endpoint)?;
+    if let Some(values_from) = &qm.values_from {
+        // Dependencies are processed first, so this is the fetch the values will be read from.
+        // Hashing it keeps the table stale until it is rebuilt from that fetch, even if an
+        // interrupted build rebuilt only the dependency.
+        let fetched_at_us = tokio::task::block_in_place(|| {
+            used_queries::get_stored_query(&*ctx.conn()?, &values_from.table)
+        })?
+        .and_then(|stored| stored.fetched_at_us)
+        .unwrap_or_default();
+        inputs.push(format!(
+            "values_from={}@{}",
+            values_from.table, fetched_at_us
+        ));
+    }
+    let hash = content_hash(&query, &qm, &inputs);
+    // The connection may be held by another task for a whole load, so wait off the async workers
+    let stored =
+        tokio::task::block_in_place(|| used_queries::get_stored_query(&*ctx.conn()?, name))?;
+    if !force && let Some(stored) = stored {
+        let unchanged = match &stored.hash {
+            Some(stored_hash) => *stored_hash == hash,
+            // Tables built before hashes were recorded are compared by the query text alone
+            None => stored.query == query,
+        };
+        let max_age = qm.max_age.or(ctx.args.max_age);
+        // A table without a recorded fetch time is taken as expired
+        let expired = max_age.is_some_and(|max_age| {
+            stored.fetched_at_us.is_none_or(|fetched_at_us| {
+                now_us().saturating_sub(fetched_at_us) > max_age.as_micros() as i64
+            })
+        });
+
+        if unchanged && expired {
+            log::info!(
+                target: &log_target,
+                "Refetching as the last fetch is older than {:?}",
+                max_age.unwrap_or_default()
+            );
+        } else if unchanged {
+            if stored.hash.is_none() {
+                tokio::task::block_in_place(|| used_queries::set_hash(&*ctx.conn()?, name, &hash))?;
+            }
+            // `[export] formats` may have changed since the table was built
+            let layer1_dist_dir = Path::new(&ctx.args.dist_dir).join("layer1");
+            let formats = &ctx.args.config.export.formats;
+            if !export::is_exported(&layer1_dist_dir, name, formats) {
+                log::info!(target: &log_target, "Exporting again as the export formats changed");
+                std::fs::create_dir_all(&layer1_dist_dir)?;
+                tokio::task::block_in_place(|| {
+                    export::export_relation_to_files(
+                        &*ctx.conn()?,
+                        "layer1",
+                        name,
+                        &layer1_dist_dir,
+                        formats,
+                    )
+                })?;
+            }
             log::info!(target: &log_target, "Skipping as the query is up-to-date");
             return Ok(());
         }
     }
 
-    let query = std::fs::read_to_string(&query_path)?;
-    let qm = sparql_query_metadata::extract_query_metadata(&query)?;
     log::info!(target: &log_target, "Using endpoint {}", qm.endpoint);
+    let options = RequestOptions {
+        endpoint: qm.endpoint.clone(),
+        format: qm.format,
+        retry: ctx.args.retry_policy(qm.retries),
+        method: qm.method,
+        default_graphs: qm.default_graphs.clone(),
+        named_graphs: qm.named_graphs.clone(),
+        parameters: qm.parameters.clone(),
+        credential: ctx.credentials.for_endpoint(&qm.endpoint).cloned(),
+        timeout: qm.timeout.unwrap_or(ctx.args.timeout),
+    };
 
-    let paths = if let Some(paginate) = qm.paginate {
-        request_with_pagination(&log_target, &query, &qm.endpoint, paginate).await?
-    } else {
-        request_single(&log_target, &query, &qm.endpoint).await?
+    let mut queries = match &qm.shard {
+        Some(shard) => sparql_query_modifier::shard_queries(&query, shard)?,
+        None => vec![query.clone()],
     };
+    if let Some(values_from) = &qm.values_from {
+        let terms = tokio::task::block_in_place(|| distinct_terms(&*ctx.conn()?, values_from))?;
+        if terms.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No values in {}.{}",
+                values_from.table,
+                values_from.column
+            ));
+        }
+        log::info!(
+            target: &log_target,
+            "Using {} value(s) of {}.{} in batches of {}",
+            terms.len(),
+            values_from.table,
+            values_from.column,
+            values_from.batch
+        );
+        queries = queries
+            .iter()
+            .map(|query| {
+                sparql_query_modifier::values_batch_queries(
+                    query,
+                    &values_from.column,
+                    &terms,
+                    values_from.batch,
+                )
+            })
+            .collect::<anyhow::Result<Vec<Vec<String>>>>()?
+            .concat();
+    }
+    let mut checkpoints = vec![];
+    for query in &queries {
+        let checkpoint = Checkpoint::new(ctx.args.work_dir(), &qm.endpoint, query)?;
+        if ctx.resume {
+            log::info!(target: &log_target, "Resuming from {}", checkpoint.dir().display());
+        } else {
+            checkpoint.reset()?;
+        }
+        checkpoints.push(checkpoint);
+    }
 
-    sparql_result_to_duckdb::sparql_results_to_duckdb(conn, name, &paths)?;
-    let layer1_dist_dir = Path::new(&args.dist_dir).join("layer1");
-    std::fs::create_dir_all(&layer1_dist_dir)?;
-    export::export_relation_to_files(conn, "layer1", name, &layer1_dist_dir)?;
+    // The results are at least as old as the start of the fetch
+    let fetched_at_us = now_us();
+    let mut paths = vec![];
+    {
+        // Wait for the endpoint first so that queued queries don't hold a job slot
+        let _endpoint_permit = ctx.endpoints.acquire(&qm.endpoint).await?;
+        let _job_permit = ctx.jobs.acquire().await?;
 
-    let add_comment_stmt = format!(
-        "COMMENT ON TABLE layer1.{} IS {}",
-        escape_sql_identifier(name),
-        escape_sql_literal(&query)
-    );
-    conn.execute(&add_comment_stmt, [])?;
+        for (i, (query, checkpoint)) in queries.iter().zip(&checkpoints).enumerate() {
+            if queries.len() > 1 {
+                log::info!(target: &log_target, "Fetching part {}/{}", i + 1, queries.len());
+            }
+            let shard_paths = if let Some(pagination) = &qm.paginate {
+                request_with_pagination(
+                    &ctx.client,
+                    &log_target,
+                    query,
+                    &options,
+                    pagination,
+                    checkpoint,
+                )
+                .await?
+            } else {
+                request_single(&ctx.client, &log_target, query, &options, checkpoint).await?
+            };
+            paths.extend(shard_paths);
+        }
+    }
+
+    // Loading is blocking and serialized on the shared connection
+    tokio::task::block_in_place(|| -> anyhow::Result<()> {
+        let conn = ctx.conn()?;
+        let coercions = sparql_result_to_duckdb::sparql_results_to_duckdb(
+            &conn,
+            name,
+            &paths,
+            &qm,
+            &ctx.progress,
+        )?;
+        for coercion in &coercions {
+            log::info!(
+                target: &log_target,
+                "Widened {} values of ?{} from {} to {}",
+                coercion.count,
+                coercion.column,
+                coercion.datatype,
+                coercion.column_type
+            );
+        }
+        // The pages are no longer needed once they have been loaded
+        for checkpoint in &checkpoints {
+            checkpoint.clear()?;
+        }
+        let layer1_dist_dir = Path::new(&ctx.args.dist_dir).join("layer1");
+        std::fs::create_dir_all(&layer1_dist_dir)?;
+        export::export_relation_to_files(
+            &conn,
+            "layer1",
+            name,
+            &layer1_dist_dir,
+            &ctx.args.config.export.formats,
+        )?;
+
+        let add_comment_stmt = format!(
+            "COMMENT ON TABLE layer1.{} IS {}",
+            escape_sql_identifier(name),
+            escape_sql_literal(&query)
+        );
+        conn.execute(&add_comment_stmt, [])?;
 
-    used_queries::touch_mtime(conn, name, &query, query_mtime_us)?;
+        used_queries::record_query(&conn, name, &query, query_mtime_us, &hash, fetched_at_us)?;
+        used_queries::record_coercions(&conn, name, &coercions)?;
+
+        Ok(())
+    })?;
+    log::info!(target: &log_target, "Done");
 
     Ok(())
 }
@@ -111,9 +630,10 @@ async fn process_query<P: AsRef<Path>>(
 fn drop_tables_for_not_existing_queries(
     conn: &duckdb::Connection,
     names: &[String],
+    dist_dir: &Path,
 ) -> anyhow::Result<()> {
     let names_in_db = used_queries::all_query_names(conn)?;
-    let layer1_dist_dir = Path::new("dist").join("layer1");
+    let layer1_dist_dir = dist_dir.join("layer1");
 
     for name in names_in_db {
         let log_target = format!("layer1/{}", name);
@@ -125,8 +645,8 @@ fn drop_tables_for_not_existing_queries(
                 &format!("DROP TABLE IF EXISTS {}", escape_sql_identifier(&name)),
                 [],
             )?;
-            for ext in &["csv", "tsv", "parquet"] {
-                let path = layer1_dist_dir.join(format!("{}.{}", name, ext));
+            for format in ExportFormat::ALL {
+                let path = layer1_dist_dir.join(format!("{}.{}", name, format.extension()));
                 if path.exists() {
                     std::fs::remove_file(&path)?;
                     log::info!(target: &log_target, "Removed {}", path.display());
@@ -138,13 +658,14 @@ fn drop_tables_for_not_existing_queries(
     Ok(())
 }
 
-pub async fn layer1(args: &Args, force: bool) -> anyhow::Result<()> {
+pub async fn layer1(args: &Args, force: bool, resume: bool, offline: bool) -> anyhow::Result<()> {
     let dest_dir = Path::new(&args.dist_dir);
     let dest_db_path = dest_dir.join("layer1.duckdb");
 
     log::info!(target: "layer1", "Output DB path: {}", dest_db_path.display());
 
     let conn = duckdb::Connection::open(dest_db_path)?;
+    args.config.apply_duckdb_settings(&conn)?;
     ensure_metadata_schema(&conn)?;
 
     let src_dir = args.layer1_queries_dir();
@@ -159,12 +680,151 @@ pub async fn layer1(args: &Args, force: bool) -> anyhow::Result<()> {
     if names.is_empty() {
         return Err(anyhow::anyhow!("No queries found in {}", src_dir.display()));
     }
-    drop_tables_for_not_existing_queries(&conn, &names)?;
+    drop_tables_for_not_existing_queries(&conn, &names, dest_dir)?;
+
+    log::info!(
+        target: "layer1",
+        "Running up to {} queries at once, {} per endpoint",
+        args.jobs,
+        args.jobs_per_endpoint
+    );
+    let progress = MultiProgress::new();
+    let cache = ResponseCache::new(args.cache_dir());
+    let ctx = Arc::new(Context {
+        args: args.clone(),
+        resume,
+        client: SparqlClient::new(
+            progress.clone(),
+            args.connect_timeout,
+            cache.clone(),
+            offline,
+        )?,
+        progress,
+        jobs: Arc::new(Semaphore::new(args.jobs.max(1))),
+        endpoints: EndpointLimiter::new(args.jobs_per_endpoint.max(1)),
+        credentials: args.credentials()?,
+        conn: Mutex::new(conn),
+    });
 
-    for name in names {
-        let query_path = src_dir.join(name).with_extension("rq");
-        process_query(&conn, query_path, args, force).await?;
+    // Queries reading values from other tables run in later waves than those tables
+    let mut dependencies = HashMap::new();
+    for name in &names {
+        let query = std::fs::read_to_string(src_dir.join(name).with_extension("rq"))?;
+        let qm = sparql_query_metadata::extract_query_metadata(&query, &args.config)?;
+        if let Some(values_from) = qm.values_from {
+            if !names.contains(&values_from.table) {
+                return Err(anyhow::anyhow!(
+                    "{} reads values from {}, which is not a Layer 1 query",
+                    name,
+                    values_from.table
+                ));
+            }
+            dependencies.insert(name.clone(), values_from.table);
+        }
+    }
+
+    let mut processed = HashSet::new();
+    let mut remaining = names;
+    while !remaining.is_empty() {
+        let (wave, rest): (Vec<String>, Vec<String>) = remaining.into_iter().partition(|name| {
+            dependencies
+                .get(name)
+                .is_none_or(|dependency| processed.contains(dependency))
+        });
+        if wave.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Circular ValuesFrom dependencies among {}",
+                rest.join(", ")
+            ));
+        }
+        remaining = rest;
+
+        let mut tasks = JoinSet::new();
+        for name in wave {
+            let query_path = src_dir.join(&name).with_extension("rq");
+            // A table reading values from a rebuilt table is found stale by its content hash
+            let ctx = ctx.clone();
+            tasks.spawn(async move { (name, process_query(ctx, query_path, force).await) });
+        }
+        // Dropping the set on the first error aborts the remaining queries;
+        // their completed pages stay in the work directory for `--resume`
+        while let Some(result) = tasks.join_next().await {
+            let (name, result) = result?;
+            result.map_err(|e| e.context(format!("Failed to build Layer 1 table {}", name)))?;
+            processed.insert(name);
+        }
+    }
+
+    // Every table is built, so what is left are pages of queries that have since been skipped,
+    // changed or deleted
+    let work_dir = args.work_dir();
+    if work_dir.exists() {
+        std::fs::remove_dir_all(&work_dir)?;
+    }
+    if let Some(cache_max_age) = args.cache_max_age {
+        let num_evicted = cache.evict(cache_max_age)?;
+        if num_evicted > 0 {
+            log::info!(target: "layer1", "Removed {} unused cached responses", num_evicted);
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paginated_build_from_file_endpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let queries_dir = dir.path().join("queries");
+        let dist_dir = dir.path().join("dist");
+        let state_dir = dir.path().join(".tabulae");
+        std::fs::create_dir_all(queries_dir.join("layer1")).unwrap();
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/clinvar");
+        std::fs::write(
+            queries_dir.join("layer1/clinvar.rq"),
+            format!(
+                "# Endpoint: file://{}\nSELECT ?clinvar ?id WHERE {{ ?clinvar <http://example.org/id> ?id }} ORDER BY ?id\n# Paginate: 2\n",
+                fixture.display()
+            ),
+        )
+        .unwrap();
+        let args = Args::try_parse_from([
+            OsStr::new("tabulae"),
+            OsStr::new("--queries-dir"),
+            queries_dir.as_os_str(),
+            OsStr::new("--dist-dir"),
+            dist_dir.as_os_str(),
+            OsStr::new("--state-dir"),
+            state_dir.as_os_str(),
+            OsStr::new("build"),
+        ])
+        .unwrap();
+
+        layer1(&args, false, false, false).await.unwrap();
+
+        let conn = duckdb::Connection::open(dist_dir.join("layer1.duckdb")).unwrap();
+        let (count, max_id, id_type): (i64, i64, String) = conn
+            .query_row(
+                "SELECT count(*), max(id), any_value(typeof(id)) FROM clinvar",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((count, max_id, id_type.as_str()), (5, 5, "BIGINT"));
+        assert!(dist_dir.join("layer1/clinvar.parquet").exists());
+        assert!(!args.work_dir().exists());
+        // Results served from files are not copied into the response cache
+        assert!(!args.cache_dir().exists());
+        drop(conn);
+
+        // Nothing changed, so the second build skips the table
+        layer1(&args, false, false, false).await.unwrap();
+    }
+}
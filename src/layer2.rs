@@ -25,6 +25,7 @@ pub fn layer2(args: &Args) -> anyhow::Result<()> {
             std::fs::remove_file(&layer2_db_path)?;
         }
         let conn = duckdb::Connection::open(&layer2_db_path)?;
+        args.config.apply_duckdb_settings(&conn)?;
         conn.prepare(&format!(
             "ATTACH {} (READ_ONLY)",
             escape_sql_literal(&layer1_db_path.to_string_lossy())
@@ -62,7 +63,13 @@ pub fn layer2(args: &Args) -> anyhow::Result<()> {
             );
             conn.execute(&add_comment_stmt, [])?;
 
-            export::export_relation_to_files(&conn, "layer2", &name, &layer2_dist_dir)?;
+            export::export_relation_to_files(
+                &conn,
+                "layer2",
+                &name,
+                &layer2_dist_dir,
+                &args.config.export.formats,
+            )?;
         }
         log::info!(target: "layer2", "Wrote {}", layer2_db_path.display());
     }
@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use oxigraph::{
+    io::RdfFormat,
+    sparql::{
+        QueryResults, SparqlEvaluator,
+        results::{QueryResultsFormat, QueryResultsSerializer},
+    },
+    store::Store,
+};
+
+use crate::{sparql_client::count_bindings, sparql_results::ResultFormat};
+
+/// `# Source: <path>` is stored as the endpoint `source:<path>`, so that local sources are
+/// scheduled, checkpointed and cached like remote endpoints
+pub const SOURCE_SCHEME: &str = "source:";
+
+pub fn source_of(endpoint: &str) -> Option<&str> {
+    endpoint.strip_prefix(SOURCE_SCHEME)
+}
+
+/// RDF files of a source: a file, all files in a directory, or a glob pattern
+pub fn source_files(source: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let path = Path::new(source);
+    let mut files = if path.is_dir() {
+        std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, _>>()?
+    } else {
+        glob::glob(source)?.collect::<Result<Vec<PathBuf>, _>>()?
+    };
+    files.retain(|file| file.is_file());
+    files.sort();
+    if files.is_empty() {
+        return Err(anyhow::anyhow!("No RDF files found for source {}", source));
+    }
+    Ok(files)
+}
+
+fn results_format(format: ResultFormat) -> QueryResultsFormat {
+    match format {
+        ResultFormat::Json => QueryResultsFormat::Json,
+        ResultFormat::Xml => QueryResultsFormat::Xml,
+        ResultFormat::Tsv => QueryResultsFormat::Tsv,
+        ResultFormat::Csv => QueryResultsFormat::Csv,
+    }
+}
+
+/// In-memory stores of the local sources, each loaded once per build and shared by all queries
+/// using the same source
+#[derive(Default)]
+pub struct LocalStores {
+    stores: Mutex<HashMap<String, Store>>,
+}
+
+impl LocalStores {
+    fn store(&self, log_target: &str, source: &str) -> anyhow::Result<Store> {
+        // Held while loading so that a source used by several queries is only loaded once
+        let mut stores = self
+            .stores
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Local store lock poisoned"))?;
+        if let Some(store) = stores.get(source) {
+            return Ok(store.clone());
+        }
+
+        let store = Store::new()?;
+        for file in source_files(source)? {
+            let format = file
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .and_then(RdfFormat::from_extension)
+                .ok_or_else(|| anyhow::anyhow!("Unknown RDF format: {}", file.display()))?;
+            log::info!(target: log_target, "Loading {}", file.display());
+            let reader = std::io::BufReader::new(std::fs::File::open(&file)?);
+            store
+                .load_from_reader(format, reader)
+                .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", file.display(), e))?;
+        }
+        stores.insert(source.to_string(), store.clone());
+
+        Ok(store)
+    }
+
+    /// Runs a query against a local source and saves the results like a response of an endpoint
+    pub fn save_sparql_result_to_file<P: AsRef<Path>>(
+        &self,
+        log_target: &str,
+        source: &str,
+        query: &str,
+        format: ResultFormat,
+        file: P,
+    ) -> anyhow::Result<usize> {
+        let store = self.store(log_target, source)?;
+
+        let mut query = SparqlEvaluator::new().parse_query(query)?;
+        // Quads from TriG or N-Quads files are in named graphs, which most queries don't name.
+        // A dataset given with FROM or FROM NAMED is kept.
+        if query.dataset().is_default_dataset() {
+            query.dataset_mut().set_default_graph_as_union();
+        }
+        let QueryResults::Solutions(solutions) = query.on_store(&store).execute()? else {
+            return Err(anyhow::anyhow!(
+                "Only SELECT queries can be run against a local source"
+            ));
+        };
+
+        let writer = std::io::BufWriter::new(std::fs::File::create(&file)?);
+        let mut serializer = QueryResultsSerializer::from_format(results_format(format))
+            .serialize_solutions_to_writer(writer, solutions.variables().to_vec())?;
+        for solution in solutions {
+            serializer.serialize(&solution?)?;
+        }
+        serializer.finish()?.flush()?;
+
+        count_bindings(file, format)
+    }
+}
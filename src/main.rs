@@ -1,14 +1,22 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, parser::ValueSource};
 
+mod checkpoint;
+mod config;
+mod credentials;
 pub mod duckdb_util;
+mod duration_util;
 pub mod export;
+mod file_endpoint;
+mod local_source;
+mod response_cache;
 
 mod sparql_client;
 mod sparql_query_metadata;
 mod sparql_query_modifier;
 mod sparql_result_to_duckdb;
+mod sparql_results;
 mod used_queries;
 
 #[cfg(feature = "frontend")]
@@ -18,7 +26,7 @@ mod layer2;
 mod manifest;
 
 /// Issue SPARQL queries
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(
     name = env!("CARGO_PKG_NAME"),
     version = env!("CARGO_PKG_VERSION"),
@@ -36,9 +44,137 @@ pub struct Args {
     /// Output directory
     #[arg(short, long, default_value = "dist")]
     dist_dir: PathBuf,
+    /// Directory for files that must not be published with the output, such as fetched pages
+    #[arg(long, default_value = ".tabulae")]
+    state_dir: PathBuf,
+    /// Number of retries for each failed SPARQL request (can be overridden by `# Retries:`)
+    #[arg(long, default_value_t = 3)]
+    retries: usize,
+    /// Initial delay before retrying a failed SPARQL request, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    retry_delay_ms: u64,
+    /// Maximum number of Layer 1 queries fetched at the same time
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    /// Maximum number of Layer 1 queries fetched at the same time from a single endpoint
+    #[arg(long, default_value_t = 2)]
+    jobs_per_endpoint: usize,
+    /// Timeout for connecting to an endpoint, such as `30s`
+    #[arg(long, default_value = "30s", value_parser = duration_util::parse_duration)]
+    connect_timeout: std::time::Duration,
+    /// Timeout for a whole SPARQL request, including the download of the results (can be
+    /// overridden by `# Timeout:`)
+    #[arg(long, default_value = "1h", value_parser = duration_util::parse_duration)]
+    timeout: std::time::Duration,
+    /// Refetch Layer 1 tables last fetched longer ago than this, such as `30d` (can be
+    /// overridden by `# MaxAge:`)
+    #[arg(long, value_parser = duration_util::parse_duration)]
+    max_age: Option<std::time::Duration>,
+    /// Remove cached responses that no build has used for longer than this, such as `90d`
+    #[arg(long, value_parser = duration_util::parse_duration)]
+    cache_max_age: Option<std::time::Duration>,
+    /// JSON file mapping endpoint URLs to credentials; secrets may be given as `env:VARIABLE`
+    #[arg(long)]
+    credentials_file: Option<PathBuf>,
+    /// Project configuration file
+    #[arg(long, default_value = config::DEFAULT_CONFIG_PATH)]
+    config_file: PathBuf,
+    /// Profile in the configuration file to build with
+    #[arg(long)]
+    profile: Option<String>,
+    #[arg(skip)]
+    config: config::Config,
 }
 
 impl Args {
+    /// Parses the command line and merges in the settings of the config file
+    fn load() -> anyhow::Result<Self> {
+        let matches = Args::command().get_matches();
+        let mut args = Args::from_arg_matches(&matches)?;
+        let from_command_line =
+            |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        let mut config = config::Config::load(&args.config_file, from_command_line("config_file"))?;
+        if !from_command_line("queries_dir")
+            && let Some(queries_dir) = &config.queries_dir
+        {
+            args.queries_dir = queries_dir.clone();
+        }
+        if !from_command_line("dist_dir")
+            && let Some(dist_dir) = &config.dist_dir
+        {
+            args.dist_dir = dist_dir.clone();
+        }
+        if !from_command_line("state_dir")
+            && let Some(state_dir) = &config.state_dir
+        {
+            args.state_dir = state_dir.clone();
+        }
+        if !from_command_line("credentials_file") && config.credentials_file.is_some() {
+            args.credentials_file = config.credentials_file.clone();
+        }
+        if !from_command_line("retries")
+            && let Some(retries) = config.requests.retries
+        {
+            args.retries = retries;
+        }
+        if !from_command_line("retry_delay_ms")
+            && let Some(retry_delay_ms) = config.requests.retry_delay_ms
+        {
+            args.retry_delay_ms = retry_delay_ms;
+        }
+        if !from_command_line("connect_timeout")
+            && let Some(connect_timeout) = &config.requests.connect_timeout
+        {
+            args.connect_timeout = duration_util::parse_duration(connect_timeout)?;
+        }
+        if !from_command_line("timeout")
+            && let Some(timeout) = &config.requests.timeout
+        {
+            args.timeout = duration_util::parse_duration(timeout)?;
+        }
+        if !from_command_line("max_age")
+            && let Some(max_age) = &config.layer1.max_age
+        {
+            args.max_age = Some(duration_util::parse_duration(max_age)?);
+        }
+        if !from_command_line("cache_max_age")
+            && let Some(cache_max_age) = &config.layer1.cache_max_age
+        {
+            args.cache_max_age = Some(duration_util::parse_duration(cache_max_age)?);
+        }
+        if !from_command_line("jobs")
+            && let Some(jobs) = config.concurrency.jobs
+        {
+            args.jobs = jobs;
+        }
+        if !from_command_line("jobs_per_endpoint")
+            && let Some(jobs_per_endpoint) = config.concurrency.jobs_per_endpoint
+        {
+            args.jobs_per_endpoint = jobs_per_endpoint;
+        }
+        if let Some(profile) = &args.profile {
+            config.select_profile(profile)?;
+            if !from_command_line("dist_dir") {
+                args.dist_dir = match config
+                    .profile
+                    .as_ref()
+                    .and_then(|(_, p)| p.dist_dir.clone())
+                {
+                    Some(dist_dir) => dist_dir,
+                    None => {
+                        let mut dist_dir = args.dist_dir.into_os_string();
+                        dist_dir.push(format!("-{}", profile));
+                        dist_dir.into()
+                    }
+                };
+            }
+        }
+        args.config = config;
+
+        Ok(args)
+    }
+
     pub fn layer1_queries_dir(&self) -> PathBuf {
         self.queries_dir.join("layer1")
     }
@@ -46,28 +182,66 @@ impl Args {
     pub fn layer2_queries_dir(&self) -> PathBuf {
         self.queries_dir.join("layer2")
     }
+
+    /// Each profile has its own work directory, as the dist directory, so that a build with
+    /// one profile doesn't remove the pages another one can still be resumed from
+    pub fn work_dir(&self) -> PathBuf {
+        match &self.profile {
+            Some(profile) => self.state_dir.join(format!("work-{}", profile)),
+            None => self.state_dir.join("work"),
+        }
+    }
+
+    pub fn cache_dir(&self) -> PathBuf {
+        self.state_dir.join("cache")
+    }
+
+    pub fn credentials(&self) -> anyhow::Result<credentials::Credentials> {
+        match &self.credentials_file {
+            Some(path) => credentials::Credentials::load(path),
+            None => Ok(credentials::Credentials::default()),
+        }
+    }
+
+    pub fn retry_policy(&self, retries: Option<usize>) -> sparql_client::RetryPolicy {
+        sparql_client::RetryPolicy {
+            max_retries: retries.unwrap_or(self.retries),
+            base_delay: std::time::Duration::from_millis(self.retry_delay_ms),
+        }
+    }
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 enum SubCommand {
     /// Build tables
     Build {
         /// Don't skip even if the output is newer than the query file
         #[arg(short, long)]
         force: bool,
+        /// Continue interrupted fetches from the pages saved under the work directory
+        #[arg(long)]
+        resume: bool,
+        /// Rebuild all Layer 1 tables from the response cache without sending any requests
+        #[arg(long)]
+        offline: bool,
     },
 }
 
-#[tokio::main(flavor = "current_thread")]
+#[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    let args = Args::parse();
+    let args = Args::load()?;
 
     std::fs::create_dir_all(&args.dist_dir)?;
 
     match args.subcommand {
-        SubCommand::Build { force } => {
-            layer1::layer1(&args, force).await?;
+        SubCommand::Build {
+            force,
+            resume,
+            offline,
+        } => {
+            // Offline builds exist to rebuild tables from cached responses, so nothing is skipped
+            layer1::layer1(&args, force || offline, resume, offline).await?;
             layer2::layer2(&args)?;
             manifest::manifest(&args)?;
 
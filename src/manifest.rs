@@ -2,7 +2,7 @@ use std::{collections::HashMap, path::Path};
 
 use duckdb::{AccessMode, Config, Connection, params};
 
-use crate::{Args, duckdb_util::escape_sql_literal};
+use crate::{Args, duckdb_util::escape_sql_literal, export::ExportFormat};
 
 #[derive(serde::Serialize, Debug)]
 struct Column {
@@ -34,6 +34,8 @@ struct Table {
 #[derive(serde::Serialize, Debug)]
 struct Manifest {
     tables: Vec<Table>,
+    /// The profile given by `--profile`
+    profile: Option<String>,
 }
 
 fn get_filesizes<P: AsRef<Path>>(
@@ -42,7 +44,8 @@ fn get_filesizes<P: AsRef<Path>>(
     collection: &str,
 ) -> HashMap<String, usize> {
     let mut sizes = HashMap::new();
-    for ext in ["csv", "tsv", "parquet"] {
+    for format in ExportFormat::ALL {
+        let ext = format.extension();
         let exported_path = dist_dir
             .as_ref()
             .join(collection)
@@ -135,6 +138,7 @@ fn summarize_tables(args: &Args, collection: &str) -> anyhow::Result<Vec<Table>>
     let config = Config::default().access_mode(AccessMode::ReadOnly)?;
     let db_path = args.dist_dir.join(format!("{}.duckdb", collection));
     let conn = Connection::open_with_flags(db_path, config)?;
+    args.config.apply_duckdb_settings(&conn)?;
 
     let mut stmt = conn.prepare("SHOW TABLES")?;
     let rows = stmt.query_map([], |row| {
@@ -174,7 +178,10 @@ pub fn manifest(args: &Args) -> anyhow::Result<()> {
         .chain(layer1_tables)
         .collect::<Vec<Table>>();
 
-    let manifest = Manifest { tables };
+    let manifest = Manifest {
+        tables,
+        profile: args.config.profile_name().map(|name| name.to_string()),
+    };
 
     let manifest_path = dest_dir.join("manifest.json");
     let manifest_file = std::fs::File::create(&manifest_path)?;
@@ -0,0 +1,93 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tempfile::NamedTempFile;
+
+use crate::{checkpoint::sha256_hex, sparql_client::RequestOptions};
+
+/// Raw SPARQL responses, addressed by the endpoint and the exact request sent for each page
+#[derive(Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+}
+
+impl ResponseCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self, query: &str, options: &RequestOptions) -> PathBuf {
+        let parameters = options
+            .parameters
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>();
+        let key = sha256_hex(&[
+            &options.endpoint,
+            query,
+            options.format.media_type(),
+            &options.default_graphs.join(" "),
+            &options.named_graphs.join(" "),
+            &parameters.join("&"),
+        ]);
+        // Spread the entries over subdirectories so that no directory gets too large
+        self.dir
+            .join(&key[..2])
+            .join(format!("{}.{}", key, options.format.extension()))
+    }
+
+    /// Marks an entry as used, so that it is not evicted
+    pub fn touch(&self, cache_path: &Path) -> anyhow::Result<()> {
+        std::fs::File::options()
+            .write(true)
+            .open(cache_path)?
+            .set_modified(SystemTime::now())?;
+        Ok(())
+    }
+
+    /// Removes the entries that have not been stored or used for longer than `max_age`, and
+    /// returns how many were removed
+    pub fn evict(&self, max_age: Duration) -> anyhow::Result<usize> {
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+        let Some(threshold) = SystemTime::now().checked_sub(max_age) else {
+            return Ok(0);
+        };
+
+        let mut num_evicted = 0;
+        for subdir in std::fs::read_dir(&self.dir)? {
+            let subdir = subdir?.path();
+            if !subdir.is_dir() {
+                continue;
+            }
+            for entry in std::fs::read_dir(&subdir)? {
+                let entry = entry?;
+                if entry.metadata()?.modified()? < threshold {
+                    std::fs::remove_file(entry.path())?;
+                    num_evicted += 1;
+                }
+            }
+            if std::fs::read_dir(&subdir)?.next().is_none() {
+                std::fs::remove_dir(&subdir)?;
+            }
+        }
+        Ok(num_evicted)
+    }
+
+    /// Copies a complete response into the cache
+    pub fn store<P: AsRef<Path>>(&self, cache_path: &Path, response: P) -> anyhow::Result<()> {
+        let dir = cache_path
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory"))?;
+        std::fs::create_dir_all(dir)?;
+        let mut temp_file = NamedTempFile::new_in(dir)?;
+        std::io::copy(&mut std::fs::File::open(response)?, &mut temp_file)?;
+        temp_file.persist(cache_path)?;
+        Ok(())
+    }
+}
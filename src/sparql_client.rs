@@ -1,48 +1,269 @@
 use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::{io::Write, path::Path};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{io::Write, path::Path, str::FromStr, sync::Arc, time::Duration};
 
-fn num_bindings(result: &serde_json::Value) -> usize {
-    result
-        .get("results")
-        .and_then(|r| r.get("bindings"))
-        .and_then(|b| b.as_array())
-        .map_or(0, |bindings| bindings.len())
+use crate::{
+    credentials::{self, Credential},
+    file_endpoint,
+    local_source::{self, LocalStores},
+    response_cache::ResponseCache,
+    sparql_results::{CountBindings, ResultFormat, read_sparql_results_file},
+};
+
+/// Upper bound for a single backoff delay, including one requested by `Retry-After`
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub max_retries: usize,
+    /// Delay before the first retry; doubled for each following retry
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter: a random delay in `[d/2, d)` where `d = base * 2^attempt`
+    fn backoff(&self, attempt: usize) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1u32 << attempt.min(16))
+            .min(MAX_RETRY_DELAY);
+        let half = exp / 2;
+        half + half.mul_f64(fastrand::f64())
+    }
+}
+
+/// The operations of the SPARQL 1.1 Protocol for sending a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RequestMethod {
+    /// `GET` with the query in the URL
+    Get,
+    /// `POST` with an `application/x-www-form-urlencoded` body
+    PostForm,
+    /// `POST` with the query as an `application/sparql-query` body
+    #[default]
+    PostDirect,
+}
+
+impl RequestMethod {
+    /// The name written in `# Method:`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestMethod::Get => "get",
+            RequestMethod::PostForm => "post-form",
+            RequestMethod::PostDirect => "post-direct",
+        }
+    }
+}
+
+impl FromStr for RequestMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "get" => Ok(RequestMethod::Get),
+            "post-form" | "post-urlencoded" => Ok(RequestMethod::PostForm),
+            "post-direct" | "post" => Ok(RequestMethod::PostDirect),
+            _ => Err(anyhow::anyhow!("Unknown request method: {}", s)),
+        }
+    }
+}
+
+/// How the queries of a Layer 1 table are sent to its endpoint
+#[derive(Debug, Clone)]
+pub struct RequestOptions {
+    pub endpoint: String,
+    pub format: ResultFormat,
+    pub retry: RetryPolicy,
+    pub method: RequestMethod,
+    pub default_graphs: Vec<String>,
+    pub named_graphs: Vec<String>,
+    /// Additional, often vendor-specific, parameters such as Virtuoso's `timeout`
+    pub parameters: Vec<(String, String)>,
+    pub credential: Option<Credential>,
+    pub timeout: Duration,
+}
+
+impl RequestOptions {
+    /// Protocol parameters other than the query itself
+    fn protocol_parameters(&self) -> Vec<(&str, &str)> {
+        let default_graphs = self
+            .default_graphs
+            .iter()
+            .map(|graph| ("default-graph-uri", graph.as_str()));
+        let named_graphs = self
+            .named_graphs
+            .iter()
+            .map(|graph| ("named-graph-uri", graph.as_str()));
+        let parameters = self
+            .parameters
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()));
+        default_graphs
+            .chain(named_graphs)
+            .chain(parameters)
+            .collect()
+    }
+
+    fn build_request(
+        &self,
+        client: &reqwest::Client,
+        query: &str,
+    ) -> anyhow::Result<reqwest::RequestBuilder> {
+        let mut url = reqwest::Url::parse(&self.endpoint)?;
+        let parameters = self.protocol_parameters();
+
+        let request = match self.method {
+            RequestMethod::Get => {
+                url.query_pairs_mut()
+                    .append_pair("query", query)
+                    .extend_pairs(parameters);
+                client.get(url)
+            }
+            RequestMethod::PostForm => {
+                let body = form_urlencoded::Serializer::new(String::new())
+                    .append_pair("query", query)
+                    .extend_pairs(parameters)
+                    .finish();
+                client
+                    .post(url)
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+                    .body(body)
+            }
+            RequestMethod::PostDirect => {
+                // Only the query goes into the body, so the other parameters go into the URL
+                if !parameters.is_empty() {
+                    url.query_pairs_mut().extend_pairs(parameters);
+                }
+                client
+                    .post(url)
+                    .header("Content-Type", "application/sparql-query")
+                    .body(query.to_owned())
+            }
+        };
+
+        let request = request
+            .header("Accept", self.format.media_type())
+            .timeout(self.timeout);
+        match &self.credential {
+            Some(credential) => credential.apply(request),
+            None => Ok(request),
+        }
+    }
+}
+
+enum RequestError {
+    /// Errors that are likely to succeed when the request is repeated
+    Transient {
+        error: anyhow::Error,
+        retry_after: Option<Duration>,
+    },
+    Fatal(anyhow::Error),
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(error: std::io::Error) -> Self {
+        RequestError::Fatal(error.into())
+    }
+}
+
+impl RequestError {
+    /// Like `From<reqwest::Error>`, but says how long the request was allowed to take
+    fn from_reqwest(error: reqwest::Error, options: &RequestOptions) -> Self {
+        if error.is_timeout() {
+            RequestError::Transient {
+                error: anyhow::Error::from(error)
+                    .context(format!("Request timed out after {:?}", options.timeout)),
+                retry_after: None,
+            }
+        } else if error.is_redirect() {
+            // A refused redirect is refused again on every retry
+            RequestError::Fatal(error.into())
+        } else {
+            error.into()
+        }
+    }
+}
+
+impl From<reqwest::Error> for RequestError {
+    fn from(error: reqwest::Error) -> Self {
+        // Connection resets, timeouts and truncated bodies all surface as reqwest errors
+        RequestError::Transient {
+            error: error.into(),
+            retry_after: None,
+        }
+    }
+}
+
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    // Only the delay-seconds form is supported; HTTP dates fall back to the computed backoff
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    let seconds: u64 = value.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds).min(MAX_RETRY_DELAY))
 }
 
-pub async fn save_sparql_result_to_file<P: AsRef<Path>>(
+fn read_num_bindings<P: AsRef<Path>>(file: P, format: ResultFormat) -> Result<usize, RequestError> {
+    let mut count = CountBindings::default();
+    // A truncated body from a dropped connection shows up here as a parse error
+    read_sparql_results_file(&file, format, &mut count).map_err(|error| {
+        RequestError::Transient {
+            error,
+            retry_after: None,
+        }
+    })?;
+
+    Ok(count.0)
+}
+
+/// Counts the bindings of a SPARQL result file saved earlier
+pub fn count_bindings<P: AsRef<Path>>(file: P, format: ResultFormat) -> anyhow::Result<usize> {
+    read_num_bindings(file, format).map_err(|e| match e {
+        RequestError::Transient { error, .. } | RequestError::Fatal(error) => error,
+    })
+}
+
+async fn try_save_sparql_result_to_file<P: AsRef<Path>>(
+    client: &reqwest::Client,
+    pb: &ProgressBar,
     query: &str,
-    endpoint: &str,
+    options: &RequestOptions,
     file: P,
-) -> anyhow::Result<usize> {
-    let pb: ProgressBar = ProgressBar::new(0);
-    pb.set_style(ProgressStyle::with_template(
-        "{spinner:.green} [{elapsed_precise}] {msg}",
-    )?);
-    pb.enable_steady_tick(std::time::Duration::from_millis(100));
-
-    let client = reqwest::Client::new();
-    pb.set_message(format!("Sending query to endpoint {}", endpoint));
-    let response = client
-        .post(endpoint)
-        .header("Content-Type", "application/sparql-query")
-        .header("Accept", "application/sparql-results+json")
-        .body(query.to_owned())
+) -> Result<usize, RequestError> {
+    pb.set_message(format!("Sending query to endpoint {}", options.endpoint));
+    let response = options
+        .build_request(client, query)
+        .map_err(RequestError::Fatal)?
         .send();
     pb.set_message("Waiting for response");
-    let response = response.await?;
+    let response = response
+        .await
+        .map_err(|e| RequestError::from_reqwest(e, options))?;
     pb.set_message(format!("Response received; {}", response.status()));
 
-    if !response.status().is_success() {
-        let error = response.text().await?;
-        return Err(anyhow::anyhow!(error));
+    let status = response.status();
+    if !status.is_success() {
+        let retry_after = parse_retry_after(response.headers());
+        let error = anyhow::anyhow!("{}: {}", status, response.text().await?);
+        return Err(if is_transient_status(status) {
+            RequestError::Transient { error, retry_after }
+        } else {
+            RequestError::Fatal(error)
+        });
     }
 
     let total_size = response.content_length().unwrap_or_default();
     pb.set_length(total_size);
-    pb.set_style(ProgressStyle::with_template(
-        "{spinner:.green} [{elapsed_precise}] {bar:20} {bytes:>12}/{total_bytes:12} ({eta}) {msg}",
-    )?);
+    pb.set_position(0);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] {prefix} {bar:20} {bytes:>12}/{total_bytes:12} ({eta}) {msg}",
+        )
+        .map_err(|e| RequestError::Fatal(e.into()))?,
+    );
 
     {
         let destination = std::fs::File::create(&file)?;
@@ -56,29 +277,252 @@ pub async fn save_sparql_result_to_file<P: AsRef<Path>>(
                 // pb.reset_elapsed();
                 pb.set_message("Receiving data");
             }
-            let chunk = chunk?;
+            let chunk = chunk.map_err(|e| RequestError::from_reqwest(e, options))?;
             pb.inc(chunk.len() as u64);
             writer.write_all(&chunk)?;
         }
+        writer.flush()?;
         pb.finish_with_message(format!(
             "Done; saved to {}",
             file.as_ref().to_str().unwrap_or_default()
         ));
+        pb.set_style(
+            ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {prefix} {msg}")
+                .map_err(|e| RequestError::Fatal(e.into()))?,
+        );
+        // ensure drop of writer to flush and close file
+    }
+
+    read_num_bindings(&file, options.format)
+}
+
+/// HTTP client, response cache and progress display shared by all Layer 1 queries
+#[derive(Clone)]
+pub struct SparqlClient {
+    client: reqwest::Client,
+    /// Used for requests with a credential; does not follow redirects to another origin
+    authenticated_client: reqwest::Client,
+    progress: MultiProgress,
+    cache: ResponseCache,
+    /// Serve every request from the cache instead of the endpoints
+    offline: bool,
+    local_stores: Arc<LocalStores>,
+}
+
+impl SparqlClient {
+    pub fn new(
+        progress: MultiProgress,
+        connect_timeout: Duration,
+        cache: ResponseCache,
+        offline: bool,
+    ) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .build()?;
+        let authenticated_client = reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .redirect(credentials::redirect_policy())
+            .build()?;
+        Ok(Self {
+            client,
+            authenticated_client,
+            progress,
+            cache,
+            offline,
+            local_stores: Arc::new(LocalStores::default()),
+        })
+    }
+
+    /// Adds a response obtained earlier, such as a checkpointed page, to the cache if missing.
+    /// Results served from local files are never cached, as they are cheap to produce again.
+    pub fn ensure_cached<P: AsRef<Path>>(
+        &self,
+        query: &str,
+        options: &RequestOptions,
+        file: P,
+    ) -> anyhow::Result<()> {
+        if file_endpoint::path_of(&options.endpoint).is_some()
+            || local_source::source_of(&options.endpoint).is_some()
+        {
+            return Ok(());
+        }
+        let cache_path = self.cache.path(query, options);
+        if cache_path.exists() {
+            self.cache.touch(&cache_path)?;
+        } else {
+            self.cache.store(&cache_path, file)?;
+        }
+        Ok(())
+    }
+
+    pub async fn save_sparql_result_to_file<P: AsRef<Path>>(
+        &self,
+        log_target: &str,
+        query: &str,
+        options: &RequestOptions,
+        file: P,
+    ) -> anyhow::Result<usize> {
+        if let Some(source) = file_endpoint::path_of(&options.endpoint) {
+            return file_endpoint::save_sparql_result_to_file(
+                &source,
+                query,
+                options.format,
+                &file,
+            )
+            .map_err(|e| e.context(format!("Failed to serve {}", options.endpoint)));
+        }
+        if let Some(source) = local_source::source_of(&options.endpoint) {
+            // Loading and querying the store is CPU-bound, and the result is not worth caching
+            return tokio::task::block_in_place(|| {
+                self.local_stores.save_sparql_result_to_file(
+                    log_target,
+                    source,
+                    query,
+                    options.format,
+                    &file,
+                )
+            })
+            .map_err(|e| e.context(format!("Failed to query source {}", source)));
+        }
+
+        let cache_path = self.cache.path(query, options);
+        if self.offline {
+            if !cache_path.exists() {
+                return Err(anyhow::anyhow!(
+                    "No cached response to a request to {} (expected at {})",
+                    options.endpoint,
+                    cache_path.display()
+                ));
+            }
+            std::fs::copy(&cache_path, &file)?;
+            self.cache.touch(&cache_path)?;
+            log::debug!(target: log_target, "Using cached response {}", cache_path.display());
+            return count_bindings(&file, options.format);
+        }
+
+        let retry = &options.retry;
+        let pb: ProgressBar = self.progress.add(ProgressBar::new(0));
         pb.set_style(ProgressStyle::with_template(
-            "{spinner:.green} [{elapsed_precise}] {msg}",
+            "{spinner:.green} [{elapsed_precise}] {prefix} {msg}",
         )?);
-        // ensure drop of writer to flush and close file
+        pb.set_prefix(log_target.to_string());
+        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+
+        let client = match options.credential {
+            Some(_) => &self.authenticated_client,
+            None => &self.client,
+        };
+        let mut attempt = 0;
+        let num_bindings = loop {
+            match try_save_sparql_result_to_file(client, &pb, query, options, &file).await {
+                Ok(num_bindings) => break num_bindings,
+                Err(RequestError::Fatal(error)) => {
+                    pb.abandon_with_message("Failed");
+                    return Err(error);
+                }
+                Err(RequestError::Transient { error, retry_after }) => {
+                    if attempt >= retry.max_retries {
+                        pb.abandon_with_message("Failed");
+                        return Err(error.context(format!(
+                            "Request to {} failed after {} retries",
+                            options.endpoint, attempt
+                        )));
+                    }
+                    let delay = retry_after.unwrap_or_else(|| retry.backoff(attempt));
+                    attempt += 1;
+                    log::warn!(
+                        target: log_target,
+                        "Request failed ({:#}); retrying in {:.1}s (retry {}/{})",
+                        error,
+                        delay.as_secs_f64(),
+                        attempt,
+                        retry.max_retries
+                    );
+                    pb.set_style(ProgressStyle::with_template(
+                        "{spinner:.green} [{elapsed_precise}] {prefix} {msg}",
+                    )?);
+                    pb.set_message(format!(
+                        "Waiting {:.1}s before retrying",
+                        delay.as_secs_f64()
+                    ));
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        };
+        if attempt > 0 {
+            log::info!(target: log_target, "Request succeeded after {} retries", attempt);
+        }
+
+        self.cache.store(&cache_path, &file)?;
+
+        pb.finish_with_message(format!(
+            "Done; {} bindings saved to {}",
+            num_bindings,
+            file.as_ref().to_str().unwrap_or_default()
+        ));
+
+        Ok(num_bindings)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_is_jittered_and_capped() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+        };
+        for (attempt, exp) in [(0, 1), (1, 2), (3, 8), (8, 256), (9, 300), (40, 300)] {
+            let exp = Duration::from_secs(exp);
+            for _ in 0..100 {
+                let delay = policy.backoff(attempt);
+                assert!(
+                    exp / 2 <= delay && delay <= exp,
+                    "{:?} at {}",
+                    delay,
+                    attempt
+                );
+            }
+        }
 
-    let data = std::fs::read_to_string(&file)?;
-    let result: serde_json::Value = serde_json::from_str(&data)?;
-    let num_bindings = num_bindings(&result);
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::ZERO,
+        };
+        assert_eq!(policy.backoff(5), Duration::ZERO);
+    }
 
-    pb.finish_with_message(format!(
-        "Done; {} bindings saved to {}",
-        num_bindings,
-        file.as_ref().to_str().unwrap_or_default()
-    ));
+    #[test]
+    fn retry_after_in_seconds() {
+        use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
 
-    Ok(num_bindings)
+        let retry_after = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, HeaderValue::from_static(value));
+            parse_retry_after(&headers)
+        };
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(retry_after("86400"), Some(MAX_RETRY_DELAY));
+        assert_eq!(retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(retry_after("-1"), None);
+        assert_eq!(parse_retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn transient_statuses() {
+        for status in [408, 429, 500, 502, 503, 504] {
+            assert!(is_transient_status(
+                reqwest::StatusCode::from_u16(status).unwrap()
+            ));
+        }
+        for status in [400, 401, 404, 501, 505] {
+            assert!(!is_transient_status(
+                reqwest::StatusCode::from_u16(status).unwrap()
+            ));
+        }
+    }
 }
@@ -1,13 +1,428 @@
+use std::{str::FromStr, time::Duration};
+
+use crate::{
+    config::Config, credentials, duration_util, file_endpoint, local_source,
+    sparql_client::RequestMethod, sparql_results::ResultFormat,
+};
+
+/// How LIMIT/OFFSET is put into a paginated query, set by `# PaginationMode: <mode>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaginationMode {
+    /// Parse the query, or fall back to `Text` if it can't be parsed
+    #[default]
+    Auto,
+    /// Parse the query and rewrite its algebra
+    Parse,
+    /// Replace or append a trailing LIMIT/OFFSET clause without parsing the query
+    Text,
+}
+
+impl PaginationMode {
+    /// The name written in `# PaginationMode:`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaginationMode::Auto => "auto",
+            PaginationMode::Parse => "parse",
+            PaginationMode::Text => "text",
+        }
+    }
+}
+
+impl FromStr for PaginationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(PaginationMode::Auto),
+            "parse" => Ok(PaginationMode::Parse),
+            "text" => Ok(PaginationMode::Text),
+            _ => Err(anyhow::anyhow!("Unknown pagination mode: {}", s)),
+        }
+    }
+}
+
+/// `# Paginate: <limit>` or `# Paginate: <limit> by ?key1 ?key2 ...`
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pagination {
+    pub limit: usize,
+    /// Variables for keyset pagination; LIMIT/OFFSET is used when empty
+    pub keys: Vec<String>,
+    pub mode: PaginationMode,
+}
+
+impl TryFrom<String> for Pagination {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl FromStr for Pagination {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let limit = words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Missing page size"))?
+            .parse()?;
+        // A page of 0 bindings would look like a full page forever
+        if limit == 0 {
+            return Err(anyhow::anyhow!("Page size must be positive"));
+        }
+        let keys = match words.next() {
+            None => vec![],
+            Some(by) if by.eq_ignore_ascii_case("by") => {
+                let keys = words
+                    .map(|key| {
+                        key.strip_prefix(['?', '$'])
+                            .map(|key| key.to_string())
+                            .ok_or_else(|| anyhow::anyhow!("Invalid pagination key: {}", key))
+                    })
+                    .collect::<anyhow::Result<Vec<String>>>()?;
+                if keys.is_empty() {
+                    return Err(anyhow::anyhow!("Missing pagination key after `by`"));
+                }
+                keys
+            }
+            Some(word) => return Err(anyhow::anyhow!("Unexpected word in Paginate: {}", word)),
+        };
+
+        Ok(Pagination {
+            limit,
+            keys,
+            mode: PaginationMode::default(),
+        })
+    }
+}
+
+/// Splits a list of SPARQL terms on whitespace, keeping quoted literals together
+fn split_terms(s: &str) -> anyhow::Result<Vec<String>> {
+    let mut terms = vec![];
+    let mut chars = s.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let mut term = String::new();
+        while let Some(c) = chars.next() {
+            if c.is_whitespace() {
+                break;
+            }
+            term.push(c);
+            if c == '"' || c == '\'' {
+                loop {
+                    let next = chars
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("Unterminated literal: {}", term))?;
+                    term.push(next);
+                    if next == '\\' {
+                        term.extend(chars.next());
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+        }
+        if !term.is_empty() {
+            terms.push(term);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+    Ok(terms)
+}
+
+/// How a query is split into shards
+pub enum ShardKind {
+    /// One shard per value, bound with VALUES
+    In(Vec<String>),
+    /// Half-open numeric ranges `[start, start + step)` up to `end`, selected with FILTER
+    Range { start: i64, end: i64, step: i64 },
+    /// One shard per string prefix, selected with FILTER(STRSTARTS(...))
+    Prefix(Vec<String>),
+}
+
+/// `# Shard: ?var in <term> ...`, `# Shard: ?var range <start> <end> step <step>` or
+/// `# Shard: ?var prefix "<prefix>" ...`
+pub struct Shard {
+    pub variable: String,
+    pub kind: ShardKind,
+}
+
+impl FromStr for Shard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (variable, rest) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow::anyhow!("Missing shard kind: {}", s))?;
+        let variable = variable
+            .strip_prefix(['?', '$'])
+            .filter(|var| !var.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Shard must start with a variable: {}", s))?
+            .to_string();
+        let rest = rest.trim_start();
+        let (kind, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let terms = split_terms(rest)?;
+
+        let kind = match kind.to_lowercase().as_str() {
+            "in" => ShardKind::In(terms),
+            "prefix" => ShardKind::Prefix(terms),
+            "range" => {
+                let [start, end, step_word, step] = terms.as_slice() else {
+                    return Err(anyhow::anyhow!(
+                        "Expected `range <start> <end> step <step>`: {}",
+                        s
+                    ));
+                };
+                if !step_word.eq_ignore_ascii_case("step") {
+                    return Err(anyhow::anyhow!("Unexpected word in Shard: {}", step_word));
+                }
+                let (start, end, step) = (start.parse()?, end.parse()?, step.parse()?);
+                if step <= 0 || end <= start {
+                    return Err(anyhow::anyhow!("Empty shard range: {}", s));
+                }
+                ShardKind::Range { start, end, step }
+            }
+            _ => return Err(anyhow::anyhow!("Unknown shard kind: {}", kind)),
+        };
+        if let ShardKind::In(terms) | ShardKind::Prefix(terms) = &kind
+            && terms.is_empty()
+        {
+            return Err(anyhow::anyhow!("Missing values to shard by: {}", s));
+        }
+
+        Ok(Shard { variable, kind })
+    }
+}
+
+/// How string values bound by `# ValuesFrom` are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValuesAs {
+    Iri,
+    Literal,
+}
+
+/// `# ValuesFrom: <table>.<column> [as iri|literal] [batch <size>]`: the query is run once per
+/// batch of the distinct values of a column of another Layer 1 table, bound to the variable of
+/// the same name
+pub struct ValuesFrom {
+    pub table: String,
+    pub column: String,
+    pub batch: usize,
+    /// Without `as`, a value is an IRI only if the table recorded it as one with `# Terms`
+    pub values_as: Option<ValuesAs>,
+}
+
+impl FromStr for ValuesFrom {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || {
+            anyhow::anyhow!(
+                "Expected `<table>.<column> [as iri|literal] [batch <size>]`: {}",
+                s
+            )
+        };
+        let mut words = s.split_whitespace();
+        let source = words.next().ok_or_else(usage)?;
+        let mut batch = 1000;
+        let mut values_as = None;
+        while let Some(word) = words.next() {
+            let value = words.next().ok_or_else(usage)?;
+            match word.to_lowercase().as_str() {
+                "batch" => batch = value.parse()?,
+                "as" => {
+                    values_as = Some(match value.to_lowercase().as_str() {
+                        "iri" => ValuesAs::Iri,
+                        "literal" => ValuesAs::Literal,
+                        _ => return Err(usage()),
+                    })
+                }
+                _ => return Err(usage()),
+            }
+        }
+        let (table, column) = source
+            .split_once('.')
+            .filter(|(table, column)| !table.is_empty() && !column.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Expected `<table>.<column>`: {}", source))?;
+        if batch == 0 {
+            return Err(anyhow::anyhow!("Batch size must be positive"));
+        }
+
+        Ok(ValuesFrom {
+            table: table.to_string(),
+            column: column.to_string(),
+            batch,
+            values_as,
+        })
+    }
+}
+
+/// `# Type: ?ncbigene_id BIGINT` forces the DuckDB type of a column instead of inferring it
+#[derive(Debug)]
+pub struct ColumnType {
+    pub variable: String,
+    pub sql_type: String,
+}
+
+impl FromStr for ColumnType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (variable, sql_type) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow::anyhow!("Expected `?<variable> <type>`: {}", s))?;
+        let variable = variable
+            .strip_prefix(['?', '$'])
+            .filter(|variable| !variable.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Type must start with a variable: {}", s))?;
+        let sql_type = sql_type.trim();
+        // The type is spliced into the CAST, so only what DuckDB type names consist of is allowed
+        let re =
+            regex::Regex::new(r"^[A-Za-z][A-Za-z0-9_]*(\s*\(\s*\d+(\s*,\s*\d+)?\s*\))?(\[\])*$")
+                .unwrap();
+        if !re.is_match(sql_type) {
+            return Err(anyhow::anyhow!("Invalid column type: {}", sql_type));
+        }
+
+        Ok(ColumnType {
+            variable: variable.to_string(),
+            sql_type: sql_type.to_uppercase(),
+        })
+    }
+}
+
+/// How the RDF term of each value is kept besides the value itself, set by `# Terms: <mode>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum TermDetails {
+    /// `<col>__kind`, `<col>__lang` and `<col>__datatype` columns next to each column
+    Columns,
+    /// Each column is a STRUCT of the value and its kind, language tag and datatype
+    Struct,
+}
+
+impl TermDetails {
+    /// The name written in `# Terms:`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TermDetails::Columns => "columns",
+            TermDetails::Struct => "struct",
+        }
+    }
+}
+
+impl TryFrom<String> for TermDetails {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl FromStr for TermDetails {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "columns" => Ok(TermDetails::Columns),
+            "struct" => Ok(TermDetails::Struct),
+            _ => Err(anyhow::anyhow!("Unknown term details: {}", s)),
+        }
+    }
+}
+
+/// `# PivotLanguages: ?label en ja` turns the language-tagged values of a variable into one
+/// column per language, such as `label_en` and `label_ja`
+#[derive(Debug)]
+pub struct LanguagePivot {
+    pub variable: String,
+    pub languages: Vec<String>,
+}
+
+impl FromStr for LanguagePivot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|w| !w.is_empty());
+        let variable = words
+            .next()
+            .and_then(|word| word.strip_prefix(['?', '$']))
+            .filter(|variable| !variable.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("PivotLanguages must start with a variable: {}", s))?;
+        let re = regex::Regex::new(r"^[A-Za-z]+(-[A-Za-z0-9]+)*$").unwrap();
+        let languages = words
+            .map(|language| {
+                if re.is_match(language) {
+                    Ok(language.to_lowercase())
+                } else {
+                    Err(anyhow::anyhow!("Invalid language tag: {}", language))
+                }
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?;
+        if languages.is_empty() {
+            return Err(anyhow::anyhow!("Missing languages to pivot: {}", s));
+        }
+
+        Ok(LanguagePivot {
+            variable: variable.to_string(),
+            languages,
+        })
+    }
+}
+
 pub struct QueryMetadata {
     pub endpoint: String,
-    pub paginate: Option<usize>,
+    pub paginate: Option<Pagination>,
+    pub retries: Option<usize>,
+    pub format: ResultFormat,
+    pub shard: Option<Shard>,
+    pub values_from: Option<ValuesFrom>,
+    pub method: RequestMethod,
+    pub default_graphs: Vec<String>,
+    pub named_graphs: Vec<String>,
+    pub parameters: Vec<(String, String)>,
+    pub timeout: Option<Duration>,
+    pub max_age: Option<Duration>,
+    pub column_types: Vec<ColumnType>,
+    pub terms: Option<TermDetails>,
+    pub language_pivots: Vec<LanguagePivot>,
+}
+
+/// Graph IRIs may be written with or without angle brackets
+fn graph_iri(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('<')
+        .and_then(|value| value.strip_suffix('>'))
+        .unwrap_or(value)
+        .to_string()
 }
 
-pub fn extract_query_metadata(query: &str) -> anyhow::Result<QueryMetadata> {
+pub fn extract_query_metadata(query: &str, config: &Config) -> anyhow::Result<QueryMetadata> {
     let re = regex::Regex::new(r"^#\s*([^:]+)\s*:\s*(.+)").unwrap();
 
     let mut endpoint = None;
     let mut paginate = None;
+    let mut pagination_mode = None;
+    let mut retries = None;
+    let mut format = ResultFormat::default();
+    let mut shard = None;
+    let mut values_from = None;
+    let mut method: Option<RequestMethod> = None;
+    let mut default_graphs = vec![];
+    let mut named_graphs = vec![];
+    let mut parameters = vec![];
+    let mut timeout = None;
+    let mut max_age = None;
+    let mut source = None;
+    let mut column_types: Vec<ColumnType> = vec![];
+    let mut terms = None;
+    let mut language_pivots: Vec<LanguagePivot> = vec![];
     for line in query.lines() {
         if let Some(caps) = re.captures(line) {
             let value = caps.get(2).unwrap().as_str().to_string();
@@ -19,13 +434,174 @@ pub fn extract_query_metadata(query: &str) -> anyhow::Result<QueryMetadata> {
                 "paginate" => {
                     paginate = Some(value.parse()?);
                 }
+                "paginationmode" => {
+                    pagination_mode = Some(value.parse()?);
+                }
+                "retries" => {
+                    retries = Some(value.parse()?);
+                }
+                "format" => {
+                    format = value.parse()?;
+                }
+                "shard" => {
+                    shard = Some(value.parse()?);
+                }
+                "valuesfrom" => {
+                    values_from = Some(value.parse()?);
+                }
+                "method" => {
+                    method = Some(value.parse()?);
+                }
+                "defaultgraph" => {
+                    default_graphs.push(config.resolve_graph(&graph_iri(&value)));
+                }
+                "namedgraph" => {
+                    named_graphs.push(config.resolve_graph(&graph_iri(&value)));
+                }
+                "timeout" => {
+                    timeout = Some(duration_util::parse_duration(&value)?);
+                }
+                "type" => {
+                    let column_type: ColumnType = value.parse()?;
+                    if column_types
+                        .iter()
+                        .any(|other| other.variable == column_type.variable)
+                    {
+                        return Err(anyhow::anyhow!(
+                            "Type is given twice for ?{}",
+                            column_type.variable
+                        ));
+                    }
+                    column_types.push(column_type);
+                }
+                "terms" => {
+                    terms = Some(value.parse()?);
+                }
+                "pivotlanguages" => {
+                    let pivot: LanguagePivot = value.parse()?;
+                    if language_pivots
+                        .iter()
+                        .any(|other| other.variable == pivot.variable)
+                    {
+                        return Err(anyhow::anyhow!(
+                            "PivotLanguages is given twice for ?{}",
+                            pivot.variable
+                        ));
+                    }
+                    language_pivots.push(pivot);
+                }
+                "source" => {
+                    source = Some(value.trim().to_string());
+                }
+                "maxage" => {
+                    max_age = Some(duration_util::parse_duration(&value)?);
+                }
+                "parameter" => {
+                    let (key, value) = value
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("Expected `<name>=<value>`: {}", value))?;
+                    parameters.push((key.trim().to_string(), value.trim().to_string()));
+                }
                 _ => {}
             }
         }
     }
 
-    let endpoint =
-        endpoint.ok_or_else(|| anyhow::anyhow!("Failed to extract endpoint from query"))?;
+    if paginate.is_none() {
+        paginate = config.layer1.paginate.clone();
+    }
+    if terms.is_none() {
+        terms = config.layer1.terms;
+    }
+    if let Some(pivot) = language_pivots.iter().find(|pivot| {
+        column_types
+            .iter()
+            .any(|column_type| column_type.variable == pivot.variable)
+    }) {
+        return Err(anyhow::anyhow!(
+            "?{} cannot be given both Type and PivotLanguages",
+            pivot.variable
+        ));
+    }
+    if let Some(mode) = pagination_mode {
+        let pagination: &mut Pagination = paginate
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("PaginationMode is given without Paginate"))?;
+        if mode == PaginationMode::Text && !pagination.keys.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Keyset pagination needs the query to be parsed"
+            ));
+        }
+        pagination.mode = mode;
+    }
+
+    let endpoint = match (endpoint, source) {
+        (Some(endpoint), None) => {
+            // The query text is stored with the table, so it must not carry secrets
+            credentials::reject_credentials_in_url(&endpoint)?;
+            config.resolve_endpoint(&endpoint).to_string()
+        }
+        (None, Some(source)) => format!("{}{}", local_source::SOURCE_SCHEME, source),
+        (Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "Endpoint and Source are mutually exclusive"
+            ));
+        }
+        (None, None) => return Err(anyhow::anyhow!("Failed to extract endpoint from query")),
+    };
+    // Recorded results only honor the trailing LIMIT/OFFSET, so anything filtering within the
+    // query would return the same bindings for every page, shard or batch
+    if file_endpoint::path_of(&endpoint).is_some() {
+        if paginate
+            .as_ref()
+            .is_some_and(|pagination| !pagination.keys.is_empty())
+        {
+            return Err(anyhow::anyhow!(
+                "Keyset pagination does not work with file:// endpoints"
+            ));
+        }
+        if shard.is_some() {
+            return Err(anyhow::anyhow!(
+                "Shard does not work with file:// endpoints"
+            ));
+        }
+        if values_from.is_some() {
+            return Err(anyhow::anyhow!(
+                "ValuesFrom does not work with file:// endpoints"
+            ));
+        }
+    }
+
+    // Local sources are queried in process, so there is no request these would apply to
+    if local_source::source_of(&endpoint).is_some() {
+        if method.is_some() {
+            return Err(anyhow::anyhow!("Method does not work with Source"));
+        }
+        if !default_graphs.is_empty() || !named_graphs.is_empty() {
+            return Err(anyhow::anyhow!(
+                "DefaultGraph and NamedGraph do not work with Source; use FROM and FROM NAMED in the query"
+            ));
+        }
+        if !parameters.is_empty() {
+            return Err(anyhow::anyhow!("Parameter does not work with Source"));
+        }
+    }
 
-    Ok(QueryMetadata { endpoint, paginate })
+    Ok(QueryMetadata {
+        endpoint,
+        paginate,
+        retries,
+        format,
+        shard,
+        values_from,
+        method: method.unwrap_or_default(),
+        default_graphs,
+        named_graphs,
+        parameters,
+        timeout,
+        max_age,
+        column_types,
+        terms,
+        language_pivots,
+    })
 }
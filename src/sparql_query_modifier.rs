@@ -1,4 +1,32 @@
-use spargebra::{Query, SparqlParser, algebra::GraphPattern};
+use std::sync::LazyLock;
+
+use spargebra::{
+    Query, SparqlParser,
+    algebra::{Expression, Function, GraphPattern, OrderExpression},
+    term::{Literal, NamedNode, Variable},
+};
+
+use crate::{
+    sparql_query_metadata::{Shard, ShardKind},
+    sparql_results::Value,
+};
+
+/// Start and length of a page within the query's own LIMIT/OFFSET, as if it were a subquery
+fn page_within(start: usize, length: Option<usize>, limit: usize, offset: usize) -> (usize, usize) {
+    let remaining = length.map(|length| length.saturating_sub(offset));
+    (
+        start.saturating_add(offset),
+        remaining.map_or(limit, |remaining| remaining.min(limit)),
+    )
+}
+
+/// Only SELECT results can be loaded as Layer 1 tables, so only SELECT queries are paginated
+fn query_pattern_mut(query: &mut Query) -> anyhow::Result<&mut GraphPattern> {
+    match query {
+        Query::Select { pattern, .. } => Ok(pattern),
+        _ => Err(anyhow::anyhow!("Only SELECT queries can be paginated")),
+    }
+}
 
 pub fn rewrite_query_limit_offset(
     query_str: &str,
@@ -6,28 +34,801 @@ pub fn rewrite_query_limit_offset(
     new_offeset: usize,
 ) -> anyhow::Result<Query> {
     let mut query = SparqlParser::new().parse_query(query_str)?;
+    let pattern = query_pattern_mut(&mut query)?;
+
+    // Solution modifiers are nested as Slice > Distinct/Reduced > Project > OrderBy, so any
+    // pattern other than Slice can be wrapped as it is; GROUP BY, aggregates and ORDER BY
+    // stay inside and are applied before the page is cut
+    match pattern {
+        GraphPattern::Slice { length, start, .. } => {
+            let (page_start, page_length) = page_within(*start, *length, new_limit, new_offeset);
+            *start = page_start;
+            *length = Some(page_length);
+        }
+        _ => {
+            *pattern = GraphPattern::Slice {
+                length: Some(new_limit),
+                start: new_offeset,
+                inner: Box::new(pattern.clone()),
+            };
+        }
+    }
 
-    match &mut query {
-        Query::Select { pattern, .. } => match pattern {
-            GraphPattern::Slice { length, start, .. } => {
-                *length = Some(new_limit);
-                *start = new_offeset;
+    Ok(query)
+}
+
+/// Whether the solutions of a query have a defined order. Without one, an endpoint may return
+/// overlapping or incomplete pages.
+pub fn has_order_by(query_str: &str) -> anyhow::Result<bool> {
+    fn ordered(pattern: &GraphPattern) -> bool {
+        match pattern {
+            GraphPattern::OrderBy { .. } => true,
+            GraphPattern::Slice { inner, .. }
+            | GraphPattern::Distinct { inner }
+            | GraphPattern::Reduced { inner }
+            | GraphPattern::Project { inner, .. } => ordered(inner),
+            _ => false,
+        }
+    }
+
+    let mut query = SparqlParser::new().parse_query(query_str)?;
+    Ok(ordered(query_pattern_mut(&mut query)?))
+}
+
+/// Splits off the trailing blank and comment lines, where magic comments are usually placed
+fn split_trailing_comments(query: &str) -> (&str, &str) {
+    let mut end = query.len();
+    while end > 0 {
+        let line_start = query[..end].rfind('\n').map_or(0, |i| i + 1);
+        let line = query[line_start..end].trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            break;
+        }
+        end = line_start.saturating_sub(1);
+    }
+    query.split_at(end)
+}
+
+/// Sets LIMIT/OFFSET of a query without parsing it, for queries using vendor extensions that
+/// spargebra rejects. A trailing LIMIT/OFFSET clause is replaced, otherwise one is appended;
+/// comments and formatting are kept as they are.
+pub fn paginate_query_text(query_str: &str, new_limit: usize, new_offeset: usize) -> String {
+    let (body, comments) = split_trailing_comments(query_str);
+    let (body, start, length) = split_trailing_slice(body);
+    let (page_start, page_length) = page_within(start, length, new_limit, new_offeset);
+
+    format!(
+        "{}\nLIMIT {} OFFSET {}{}",
+        body.trim_end(),
+        page_length,
+        page_start,
+        comments
+    )
+}
+
+/// Splits off a trailing LIMIT/OFFSET clause, returning the rest of the query with the offset
+/// and limit
+fn split_trailing_slice(body: &str) -> (&str, usize, Option<usize>) {
+    let trailing_clause = regex::Regex::new(r"(?i)(?:\b(?:LIMIT|OFFSET)\s+\d+\s*)+$").unwrap();
+    let clause_part = regex::Regex::new(r"(?i)\b(LIMIT|OFFSET)\s+(\d+)").unwrap();
+
+    let Some(m) = trailing_clause.find(body) else {
+        return (body, 0, None);
+    };
+    let mut start = 0;
+    let mut length = None;
+    for caps in clause_part.captures_iter(m.as_str()) {
+        // The numbers are matched by \d+, so parsing only fails on overflow
+        let n = caps[2].parse().unwrap_or(usize::MAX);
+        if caps[1].eq_ignore_ascii_case("limit") {
+            length = Some(n);
+        } else {
+            start = n;
+        }
+    }
+    (&body[..m.start()], start, length)
+}
+
+/// The OFFSET and LIMIT at the end of a query, as set by either pagination mode
+pub fn trailing_slice(query_str: &str) -> (usize, Option<usize>) {
+    let (body, _) = split_trailing_comments(query_str);
+    let (_, start, length) = split_trailing_slice(body);
+    (start, length)
+}
+
+/// Textual counterpart of `has_order_by` for queries that can't be parsed
+pub fn has_order_by_text(query_str: &str) -> bool {
+    let order_by = regex::Regex::new(r"(?i)\bORDER\s+BY\b").unwrap();
+    let without_comments = query_str
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<&str>>()
+        .join("\n");
+    order_by.is_match(&without_comments)
+}
+
+/// Byte offsets where the top-level `{ ... }` blocks of a query start, skipping braces in
+/// literals, IRIs and comments
+fn top_level_blocks(query_str: &str) -> anyhow::Result<Vec<usize>> {
+    let bytes = query_str.as_bytes();
+    let mut blocks = vec![];
+    let mut depth = 0usize;
+    let mut block_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            quote @ (b'"' | b'\'') => {
+                let long = bytes[i..].starts_with(&[quote; 3]);
+                i += if long { 3 } else { 1 };
+                loop {
+                    match bytes.get(i) {
+                        None => return Err(anyhow::anyhow!("Unterminated literal in the query")),
+                        Some(b'\\') => i += 1,
+                        Some(&c) if c == quote && !long => break,
+                        Some(&c) if c == quote && bytes[i..].starts_with(&[quote; 3]) => {
+                            i += 2;
+                            break;
+                        }
+                        _ => {}
+                    }
+                    i += 1;
+                }
+            }
+            b'<' => {
+                // `<` is either an IRI or the less-than operator; IRIs can't contain whitespace
+                let iri_len = bytes[i + 1..].iter().position(|&c| {
+                    c == b'>' || c.is_ascii_whitespace() || b"<\"{}|^`\\".contains(&c)
+                });
+                if let Some(len) = iri_len
+                    && bytes[i + 1 + len] == b'>'
+                {
+                    i += 1 + len;
+                }
             }
-            GraphPattern::Project { .. } | GraphPattern::Distinct { .. } => {
-                *pattern = GraphPattern::Slice {
-                    length: Some(new_limit),
-                    start: new_offeset,
-                    inner: Box::new(pattern.clone()),
-                };
+            b'{' => {
+                if depth == 0 {
+                    block_start = i;
+                }
+                depth += 1;
+            }
+            b'}' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| anyhow::anyhow!("Unbalanced `}}` in the query"))?;
+                if depth == 0 {
+                    blocks.push(block_start);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    if depth != 0 {
+        return Err(anyhow::anyhow!("Unbalanced `{{` in the query"));
+    }
+    Ok(blocks)
+}
+
+/// Inserts `pattern` at the beginning of the WHERE clause without parsing the query, so that
+/// vendor extensions and formatting are kept
+pub fn inject_into_where(query_str: &str, pattern: &str) -> anyhow::Result<String> {
+    let where_keyword = regex::Regex::new(r"(?i)\bWHERE\s*$").unwrap();
+    let blocks = top_level_blocks(query_str)?;
+    // The WHERE keyword is optional except after a CONSTRUCT template, so without it the
+    // first block is the group pattern
+    let start = blocks
+        .iter()
+        .find(|start| where_keyword.is_match(&query_str[..**start]))
+        .or(blocks.first())
+        .ok_or_else(|| anyhow::anyhow!("Failed to find the WHERE clause of the query"))?;
+
+    Ok(format!(
+        "{}\n  {}\n{}",
+        &query_str[..start + 1],
+        pattern,
+        &query_str[start + 1..]
+    ))
+}
+
+/// One query per shard, each restricted to its part of the solutions
+pub fn shard_queries(query_str: &str, shard: &Shard) -> anyhow::Result<Vec<String>> {
+    let var = format!("?{}", shard.variable);
+    let patterns = match &shard.kind {
+        ShardKind::In(terms) => terms
+            .iter()
+            .map(|term| format!("VALUES {} {{ {} }}", var, term))
+            .collect::<Vec<String>>(),
+        ShardKind::Range { start, end, step } => {
+            let mut patterns = vec![];
+            let mut lower = *start;
+            while lower < *end {
+                let upper = lower.saturating_add(*step).min(*end);
+                patterns.push(format!(
+                    "FILTER({} >= {} && {} < {})",
+                    var, lower, var, upper
+                ));
+                lower = upper;
             }
-            _ => {
-                return Err(anyhow::anyhow!("Unsupported graph pattern type"));
+            patterns
+        }
+        ShardKind::Prefix(prefixes) => prefixes
+            .iter()
+            .map(|prefix| format!("FILTER(STRSTARTS(STR({}), {}))", var, prefix))
+            .collect(),
+    };
+
+    patterns
+        .iter()
+        .map(|pattern| inject_into_where(query_str, pattern))
+        .collect()
+}
+
+/// The offset DuckDB writes at the end of a TIMESTAMP WITH TIME ZONE, such as `+09`
+static UTC_OFFSET: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"([+-]\d\d)$").unwrap());
+
+/// Writes a value read from DuckDB as a SPARQL term. Numbers, dates and times are written as
+/// literals of the XSD datatype their column type is inferred from, and other values as plain
+/// literals. Layer 1 tables keep only the lexical form of a value, so whether it is an IRI is
+/// told by the caller; CURIE-like IDs such as `HGNC:5` are literals, not IRIs.
+pub fn sparql_term_from_sql(value: &str, sql_type: &str, is_iri: bool) -> String {
+    let typed = |value: &str, datatype: &str| {
+        format!(
+            "\"{}\"^^<http://www.w3.org/2001/XMLSchema#{}>",
+            value, datatype
+        )
+    };
+    match sql_type {
+        _ if is_iri => format!("<{}>", value),
+        "BIGINT" | "INTEGER" | "SMALLINT" | "TINYINT" | "HUGEINT" | "UBIGINT" | "UINTEGER"
+        | "USMALLINT" | "UTINYINT" | "BOOLEAN" => value.to_string(),
+        // A bare numeral with a decimal point is an xsd:decimal. DuckDB pads the fraction to the
+        // scale of the column, which the endpoint's lexical form most likely doesn't have.
+        _ if sql_type.starts_with("DECIMAL(") => match value.split_once('.') {
+            Some((integer, fraction)) => {
+                let fraction = fraction.trim_end_matches('0');
+                format!(
+                    "{}.{}",
+                    integer,
+                    if fraction.is_empty() { "0" } else { fraction }
+                )
             }
+            None => format!("{}.0", value),
         },
-        _ => {
-            return Err(anyhow::anyhow!("Unsupported query type"));
+        "DOUBLE" | "FLOAT" => {
+            let value = match value.to_lowercase().as_str() {
+                "inf" | "infinity" => "INF",
+                "-inf" | "-infinity" => "-INF",
+                "nan" | "-nan" => "NaN",
+                _ => value,
+            };
+            typed(
+                value,
+                if sql_type == "DOUBLE" {
+                    "double"
+                } else {
+                    "float"
+                },
+            )
+        }
+        "DATE" => typed(value, "date"),
+        // DuckDB separates the date and time with a space and writes offsets as `+09`
+        "TIMESTAMP" | "TIMESTAMP WITH TIME ZONE" => {
+            let value = value.replacen(' ', "T", 1);
+            typed(&UTC_OFFSET.replace(&value, "${1}:00"), "dateTime")
         }
+        "TIME" => typed(value, "time"),
+        _ => format!(
+            "\"{}\"",
+            value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")
+        ),
     }
+}
+
+/// One query per batch of terms, each bound to `variable` with VALUES
+pub fn values_batch_queries(
+    query_str: &str,
+    variable: &str,
+    terms: &[String],
+    batch: usize,
+) -> anyhow::Result<Vec<String>> {
+    terms
+        .chunks(batch)
+        .map(|chunk| {
+            inject_into_where(
+                query_str,
+                &format!("VALUES ?{} {{ {} }}", variable, chunk.join(" ")),
+            )
+        })
+        .collect()
+}
+
+/// Finds the pattern below the projection of a SELECT query
+fn projection_mut(pattern: &mut GraphPattern) -> Option<(&mut GraphPattern, &[Variable])> {
+    match pattern {
+        GraphPattern::Slice { inner, .. }
+        | GraphPattern::Distinct { inner }
+        | GraphPattern::Reduced { inner } => projection_mut(inner),
+        GraphPattern::Project { inner, variables } => Some((inner.as_mut(), variables.as_slice())),
+        _ => None,
+    }
+}
+
+/// Datatypes whose literals can be compared with `>` in SPARQL
+const COMPARABLE_DATATYPES: [&str; 23] = [
+    "string",
+    "boolean",
+    "integer",
+    "decimal",
+    "float",
+    "double",
+    "nonPositiveInteger",
+    "negativeInteger",
+    "long",
+    "int",
+    "short",
+    "byte",
+    "nonNegativeInteger",
+    "unsignedLong",
+    "unsignedInt",
+    "unsignedShort",
+    "unsignedByte",
+    "positiveInteger",
+    "date",
+    "dateTime",
+    "dateTimeStamp",
+    "time",
+    "gYear",
+];
+
+/// Converts a value received in an earlier page into an expression comparable with its variable
+fn key_expressions(variable: &Variable, value: &Value) -> anyhow::Result<(Expression, Expression)> {
+    let var = Expression::Variable(variable.clone());
+    // `>` on other literals is a type error, which would silently filter out every solution
+    let not_comparable = |what: &str| {
+        anyhow::anyhow!(
+            "Pagination key ?{} has a {} value, which cannot be compared",
+            variable.as_str(),
+            what
+        )
+    };
+    match value.value_type.as_str() {
+        // IRIs can be ordered but not compared with `>`, so compare their string forms instead
+        "uri" => Ok((
+            Expression::FunctionCall(Function::Str, vec![var]),
+            Expression::Literal(Literal::new_simple_literal(&value.value)),
+        )),
+        "literal" | "typed-literal" => {
+            let literal = if value.xml_lang.is_some() {
+                return Err(not_comparable("language-tagged"));
+            } else if let Some(datatype) = &value.datatype {
+                let comparable = datatype
+                    .strip_prefix("http://www.w3.org/2001/XMLSchema#")
+                    .is_some_and(|name| COMPARABLE_DATATYPES.contains(&name));
+                if !comparable {
+                    return Err(not_comparable(&format!("<{}>", datatype)));
+                }
+                Literal::new_typed_literal(&value.value, NamedNode::new(datatype)?)
+            } else {
+                Literal::new_simple_literal(&value.value)
+            };
+            Ok((var, Expression::Literal(literal)))
+        }
+        value_type => Err(not_comparable(value_type)),
+    }
+}
+
+/// Builds `(k1 > v1) || (k1 = v1 && ((k2 > v2) || ...))` for a composite key
+fn keys_after(keys: &[(Expression, Expression)]) -> Option<Expression> {
+    let ((key, value), rest) = keys.split_first()?;
+    let greater = Expression::Greater(Box::new(key.clone()), Box::new(value.clone()));
+    Some(match keys_after(rest) {
+        None => greater,
+        Some(rest) => Expression::Or(
+            Box::new(greater),
+            Box::new(Expression::And(
+                Box::new(Expression::Equal(
+                    Box::new(key.clone()),
+                    Box::new(value.clone()),
+                )),
+                Box::new(rest),
+            )),
+        ),
+    })
+}
+
+/// Rewrites a SELECT query for keyset pagination: the solutions are ordered by `keys`, only
+/// those after the last values of the previous page (`after`) are kept, and at most `limit` of
+/// them are returned.
+pub fn rewrite_query_keyset(
+    query_str: &str,
+    limit: usize,
+    keys: &[String],
+    after: Option<&[Value]>,
+) -> anyhow::Result<Query> {
+    let mut query = SparqlParser::new().parse_query(query_str)?;
+
+    let Query::Select { pattern, .. } = &mut query else {
+        return Err(anyhow::anyhow!(
+            "Keyset pagination is only supported for SELECT queries"
+        ));
+    };
+    // The pages follow the order of the keys, not that of the query, so a slice of the query
+    // itself could not be honoured
+    if matches!(pattern, GraphPattern::Slice { .. }) {
+        return Err(anyhow::anyhow!(
+            "Keyset pagination does not work with queries that have their own LIMIT or OFFSET"
+        ));
+    }
+    let (inner, projected) =
+        projection_mut(pattern).ok_or_else(|| anyhow::anyhow!("Unsupported graph pattern type"))?;
+
+    let keys = keys
+        .iter()
+        .map(|key| Variable::new(key.as_str()))
+        .collect::<Result<Vec<Variable>, _>>()?;
+    if let Some(key) = keys.iter().find(|key| !projected.contains(key)) {
+        return Err(anyhow::anyhow!(
+            "Pagination key ?{} must be selected so that the last value of a page can be read",
+            key.as_str()
+        ));
+    }
+
+    let mut where_pattern = std::mem::replace(inner, GraphPattern::Bgp { patterns: vec![] });
+    // The keys define the order; any ORDER BY of the query itself would break the pagination
+    if let GraphPattern::OrderBy { inner, .. } = where_pattern {
+        where_pattern = *inner;
+    }
+    if let Some(after) = after {
+        let comparisons = keys
+            .iter()
+            .zip(after)
+            .map(|(key, value)| key_expressions(key, value))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if let Some(expr) = keys_after(&comparisons) {
+            where_pattern = GraphPattern::Filter {
+                expr,
+                inner: Box::new(where_pattern),
+            };
+        }
+    }
+    *inner = GraphPattern::OrderBy {
+        inner: Box::new(where_pattern),
+        expression: keys
+            .into_iter()
+            .map(|key| OrderExpression::Asc(Expression::Variable(key)))
+            .collect(),
+    };
+
+    *pattern = GraphPattern::Slice {
+        length: Some(limit),
+        start: 0,
+        inner: Box::new(pattern.clone()),
+    };
 
     Ok(query)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_offset_only_for_select() {
+        let query = rewrite_query_limit_offset("SELECT * WHERE { ?s ?p ?o } LIMIT 25", 10, 20)
+            .unwrap()
+            .to_string();
+        assert!(query.contains("LIMIT 5"), "{}", query);
+        assert!(query.contains("OFFSET 20"), "{}", query);
+
+        for query in [
+            "CONSTRUCT { ?s ?p ?o } WHERE { ?s ?p ?o }",
+            "DESCRIBE ?s WHERE { ?s ?p ?o }",
+            "ASK { ?s ?p ?o }",
+        ] {
+            assert!(
+                rewrite_query_limit_offset(query, 10, 0).is_err(),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_comments_are_split_off() {
+        assert_eq!(
+            split_trailing_comments("SELECT * WHERE { ?s ?p ?o }\n# Paginate: 10\n\n"),
+            ("SELECT * WHERE { ?s ?p ?o }", "\n# Paginate: 10\n\n")
+        );
+        assert_eq!(
+            split_trailing_comments("# Endpoint: x\nSELECT * WHERE { ?s ?p ?o }"),
+            ("# Endpoint: x\nSELECT * WHERE { ?s ?p ?o }", "")
+        );
+    }
+
+    #[test]
+    fn trailing_slice_in_either_order() {
+        assert_eq!(
+            split_trailing_slice("SELECT * WHERE { ?s ?p ?o } LIMIT 10 OFFSET 20"),
+            ("SELECT * WHERE { ?s ?p ?o } ", 20, Some(10))
+        );
+        assert_eq!(
+            split_trailing_slice("SELECT * WHERE { ?s ?p ?o }\noffset 20\nlimit 10\n"),
+            ("SELECT * WHERE { ?s ?p ?o }\n", 20, Some(10))
+        );
+        assert_eq!(
+            split_trailing_slice("SELECT * WHERE { ?s ?p ?o } OFFSET 5"),
+            ("SELECT * WHERE { ?s ?p ?o } ", 5, None)
+        );
+        assert_eq!(
+            split_trailing_slice("SELECT ?limit WHERE { ?s ?p ?limit }"),
+            ("SELECT ?limit WHERE { ?s ?p ?limit }", 0, None)
+        );
+    }
+
+    #[test]
+    fn trailing_slice_before_magic_comments() {
+        assert_eq!(
+            trailing_slice("SELECT * WHERE { ?s ?p ?o }\nLIMIT 2 OFFSET 4\n# Paginate: 2\n\n"),
+            (4, Some(2))
+        );
+        assert_eq!(
+            trailing_slice("# Endpoint: file://fixtures/\nSELECT * WHERE { ?s ?p ?o }\n"),
+            (0, None)
+        );
+    }
+
+    #[test]
+    fn paginate_text_within_existing_slice() {
+        let query = "SELECT * WHERE { ?s ?p ?o } LIMIT 100 OFFSET 50\n# Paginate: 30\n";
+        assert_eq!(
+            paginate_query_text(query, 30, 60),
+            "SELECT * WHERE { ?s ?p ?o }\nLIMIT 30 OFFSET 110\n# Paginate: 30\n"
+        );
+        assert_eq!(
+            paginate_query_text(query, 30, 90),
+            "SELECT * WHERE { ?s ?p ?o }\nLIMIT 10 OFFSET 140\n# Paginate: 30\n"
+        );
+        assert_eq!(
+            paginate_query_text("SELECT * WHERE { ?s ?p ?o }", 10, 20),
+            "SELECT * WHERE { ?s ?p ?o }\nLIMIT 10 OFFSET 20"
+        );
+    }
+
+    #[test]
+    fn top_level_blocks_skip_literals_iris_and_comments() {
+        let query = "PREFIX ex: <http://example.org/#>\n\
+            SELECT * WHERE {\n\
+            # { in a comment\n\
+            ?s ex:p \"}\" , \'\'\'it's } here\'\'\' .\n\
+            FILTER(?o<3)\n\
+            { ?s ex:q ?o }\n\
+            }\n";
+        assert_eq!(
+            top_level_blocks(query).unwrap(),
+            vec![query.find('{').unwrap()]
+        );
+
+        let query = "CONSTRUCT { ?s ?p ?o } WHERE { ?s ?p ?o }";
+        assert_eq!(top_level_blocks(query).unwrap(), vec![10, 29]);
+
+        assert!(top_level_blocks("SELECT * WHERE { ?s ?p ?o ").is_err());
+        assert!(top_level_blocks("SELECT * WHERE { ?s ?p ?o }}").is_err());
+        assert!(top_level_blocks("SELECT * WHERE { ?s ?p \"o }").is_err());
+    }
+
+    #[test]
+    fn values_are_injected_into_where() {
+        let values = "VALUES ?s { <http://example.org/a> }";
+        assert_eq!(
+            inject_into_where("SELECT * WHERE { ?s ?p ?o }", values).unwrap(),
+            "SELECT * WHERE {\n  VALUES ?s { <http://example.org/a> }\n ?s ?p ?o }"
+        );
+        assert_eq!(
+            inject_into_where("CONSTRUCT { ?s ?p ?o } where { ?s ?p ?o }", values).unwrap(),
+            "CONSTRUCT { ?s ?p ?o } where {\n  VALUES ?s { <http://example.org/a> }\n ?s ?p ?o }"
+        );
+        assert_eq!(
+            inject_into_where("SELECT * { ?s ?p ?o }", values).unwrap(),
+            "SELECT * {\n  VALUES ?s { <http://example.org/a> }\n ?s ?p ?o }"
+        );
+        assert!(inject_into_where("ASK", values).is_err());
+    }
+
+    #[test]
+    fn sparql_terms_from_sql_types() {
+        assert_eq!(sparql_term_from_sql("42", "UBIGINT", false), "42");
+        assert_eq!(sparql_term_from_sql("true", "BOOLEAN", false), "true");
+        assert_eq!(sparql_term_from_sql("12.50", "DECIMAL(7,2)", false), "12.5");
+        assert_eq!(sparql_term_from_sql("12.00", "DECIMAL(7,2)", false), "12.0");
+        assert_eq!(sparql_term_from_sql("12", "DECIMAL(2,0)", false), "12.0");
+        assert_eq!(
+            sparql_term_from_sql("1.5", "DOUBLE", false),
+            "\"1.5\"^^<http://www.w3.org/2001/XMLSchema#double>"
+        );
+        assert_eq!(
+            sparql_term_from_sql("-inf", "FLOAT", false),
+            "\"-INF\"^^<http://www.w3.org/2001/XMLSchema#float>"
+        );
+        assert_eq!(
+            sparql_term_from_sql("2020-01-01", "DATE", false),
+            "\"2020-01-01\"^^<http://www.w3.org/2001/XMLSchema#date>"
+        );
+        assert_eq!(
+            sparql_term_from_sql("2020-01-01 12:34:56", "TIMESTAMP", false),
+            "\"2020-01-01T12:34:56\"^^<http://www.w3.org/2001/XMLSchema#dateTime>"
+        );
+        assert_eq!(
+            sparql_term_from_sql(
+                "2020-01-01 12:34:56.5+09",
+                "TIMESTAMP WITH TIME ZONE",
+                false
+            ),
+            "\"2020-01-01T12:34:56.5+09:00\"^^<http://www.w3.org/2001/XMLSchema#dateTime>"
+        );
+        assert_eq!(
+            sparql_term_from_sql("12:34:56", "TIME", false),
+            "\"12:34:56\"^^<http://www.w3.org/2001/XMLSchema#time>"
+        );
+        assert_eq!(
+            sparql_term_from_sql("http://example.org/a", "VARCHAR", true),
+            "<http://example.org/a>"
+        );
+        assert_eq!(
+            sparql_term_from_sql("HGNC:5", "VARCHAR", false),
+            "\"HGNC:5\""
+        );
+        assert_eq!(
+            sparql_term_from_sql("say \"hi\"\n", "VARCHAR", false),
+            "\"say \\\"hi\\\"\\n\""
+        );
+    }
+
+    const XSD_INTEGER: &str = "http://www.w3.org/2001/XMLSchema#integer";
+
+    fn value(
+        value_type: &str,
+        value: &str,
+        xml_lang: Option<&str>,
+        datatype: Option<&str>,
+    ) -> Value {
+        Value {
+            value_type: value_type.to_string(),
+            xml_lang: xml_lang.map(|lang| lang.to_string()),
+            datatype: datatype.map(|datatype| datatype.to_string()),
+            value: value.to_string(),
+        }
+    }
+
+    /// The pattern below the ORDER BY of a query rewritten for keyset pagination
+    fn keyset_parts(query: &Query) -> (Option<usize>, &[OrderExpression], &GraphPattern) {
+        let Query::Select {
+            pattern: GraphPattern::Slice { inner, length, .. },
+            ..
+        } = query
+        else {
+            panic!("Expected a sliced SELECT query: {}", query);
+        };
+        let GraphPattern::Project { inner, .. } = inner.as_ref() else {
+            panic!("Expected a projection: {}", query);
+        };
+        let GraphPattern::OrderBy { inner, expression } = inner.as_ref() else {
+            panic!("Expected an ORDER BY below the projection: {}", query);
+        };
+        (*length, expression, inner)
+    }
+
+    #[test]
+    fn keys_after_composite_key() {
+        let var = |name: &str| Expression::Variable(Variable::new_unchecked(name));
+        let literal = |value: &str| Expression::Literal(Literal::new_simple_literal(value));
+        assert_eq!(keys_after(&[]), None);
+        assert_eq!(
+            keys_after(&[(var("a"), literal("1"))]),
+            Some(Expression::Greater(
+                Box::new(var("a")),
+                Box::new(literal("1"))
+            ))
+        );
+        assert_eq!(
+            keys_after(&[(var("a"), literal("1")), (var("b"), literal("2"))]),
+            Some(Expression::Or(
+                Box::new(Expression::Greater(
+                    Box::new(var("a")),
+                    Box::new(literal("1"))
+                )),
+                Box::new(Expression::And(
+                    Box::new(Expression::Equal(
+                        Box::new(var("a")),
+                        Box::new(literal("1"))
+                    )),
+                    Box::new(Expression::Greater(
+                        Box::new(var("b")),
+                        Box::new(literal("2"))
+                    )),
+                )),
+            ))
+        );
+    }
+
+    #[test]
+    fn keyset_replaces_order_by_and_compares_iris_as_strings() {
+        let query = "SELECT ?s ?n WHERE { ?s <http://example.org/n> ?n } ORDER BY DESC(?n)";
+        let keys = ["s".to_string(), "n".to_string()];
+        let s = Variable::new_unchecked("s");
+        let n = Variable::new_unchecked("n");
+
+        let first = rewrite_query_keyset(query, 10, &keys, None).unwrap();
+        let (length, order, inner) = keyset_parts(&first);
+        assert_eq!(length, Some(10));
+        assert_eq!(
+            order,
+            [
+                OrderExpression::Asc(Expression::Variable(s.clone())),
+                OrderExpression::Asc(Expression::Variable(n.clone())),
+            ]
+        );
+        assert!(matches!(inner, GraphPattern::Bgp { .. }), "{}", first);
+
+        let after = [
+            value("uri", "http://example.org/a", None, None),
+            value("literal", "42", None, Some(XSD_INTEGER)),
+        ];
+        let next = rewrite_query_keyset(query, 10, &keys, Some(&after)).unwrap();
+        let (_, _, inner) = keyset_parts(&next);
+        let GraphPattern::Filter { expr, inner } = inner else {
+            panic!("Expected a FILTER below the ORDER BY: {}", next);
+        };
+        assert!(
+            matches!(inner.as_ref(), GraphPattern::Bgp { .. }),
+            "{}",
+            next
+        );
+        let expected = keys_after(&[
+            (
+                Expression::FunctionCall(Function::Str, vec![Expression::Variable(s)]),
+                Expression::Literal(Literal::new_simple_literal("http://example.org/a")),
+            ),
+            (
+                Expression::Variable(n),
+                Expression::Literal(Literal::new_typed_literal(
+                    "42",
+                    NamedNode::new_unchecked(XSD_INTEGER),
+                )),
+            ),
+        ]);
+        assert_eq!(Some(expr), expected.as_ref());
+    }
+
+    #[test]
+    fn keyset_rejects_unselected_keys_and_incomparable_values() {
+        let query = "SELECT ?o WHERE { ?s ?p ?o }";
+        let keys = ["o".to_string()];
+        assert!(rewrite_query_keyset(query, 10, &["s".to_string()], None).is_err());
+        assert!(rewrite_query_keyset("ASK { ?s ?p ?o }", 10, &keys, None).is_err());
+        assert!(rewrite_query_keyset(&format!("{} LIMIT 500", query), 10, &keys, None).is_err());
+
+        for after in [
+            value("bnode", "b0", None, None),
+            value("literal", "x", Some("en"), None),
+            value("literal", "x", None, Some("http://example.org/custom")),
+        ] {
+            assert!(
+                rewrite_query_keyset(query, 10, &keys, Some(&[after.clone()])).is_err(),
+                "{:?}",
+                after
+            );
+        }
+        let after = [value("literal", "x", None, None)];
+        assert!(rewrite_query_keyset(query, 10, &keys, Some(&after)).is_ok());
+    }
+}
@@ -1,179 +1,796 @@
-use std::{collections::HashMap, io::Write, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
-use duckdb::{Connection, params};
-use indicatif::{ProgressBar, ProgressStyle};
+use duckdb::{Appender, Connection, appender_params_from_iter, params};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
-use crate::duckdb_util::{escape_sql_identifier, escape_sql_literal};
+use crate::{
+    duckdb_util::{escape_sql_identifier, escape_sql_literal},
+    sparql_query_metadata::{ColumnType, QueryMetadata, TermDetails},
+    sparql_results::{Binding, BindingHandler, read_sparql_results_file},
+};
 
-#[derive(serde::Deserialize, Debug)]
-pub struct SparqlResults {
-    #[allow(dead_code)]
-    pub head: Head,
-    pub results: Results,
+const XSD: &str = "http://www.w3.org/2001/XMLSchema#";
+
+/// Position of an XSD datatype in the lattice that mixed datatypes of a column are widened in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum XsdKind {
+    /// Integers of the DuckDB integer type with the given width; the unbounded `xsd:integer`
+    /// types are read as BIGINT unless their values have too many digits
+    Int {
+        bits: u8,
+        signed: bool,
+    },
+    Decimal,
+    Float,
+    Double,
+    Boolean,
+    Date,
+    DateTime,
+    DateTimeStamp,
+    Time,
+    GYear,
+}
+
+impl XsdKind {
+    fn of(datatype: &str) -> Option<Self> {
+        let kind = match datatype.strip_prefix(XSD)? {
+            "integer" | "nonNegativeInteger" | "positiveInteger" | "nonPositiveInteger"
+            | "negativeInteger" | "long" => XsdKind::Int {
+                bits: 64,
+                signed: true,
+            },
+            "int" => XsdKind::Int {
+                bits: 32,
+                signed: true,
+            },
+            "short" => XsdKind::Int {
+                bits: 16,
+                signed: true,
+            },
+            "byte" => XsdKind::Int {
+                bits: 8,
+                signed: true,
+            },
+            "unsignedLong" => XsdKind::Int {
+                bits: 64,
+                signed: false,
+            },
+            "unsignedInt" => XsdKind::Int {
+                bits: 32,
+                signed: false,
+            },
+            "unsignedShort" => XsdKind::Int {
+                bits: 16,
+                signed: false,
+            },
+            "unsignedByte" => XsdKind::Int {
+                bits: 8,
+                signed: false,
+            },
+            "decimal" => XsdKind::Decimal,
+            "float" => XsdKind::Float,
+            "double" => XsdKind::Double,
+            "boolean" => XsdKind::Boolean,
+            "date" => XsdKind::Date,
+            "dateTime" => XsdKind::DateTime,
+            "dateTimeStamp" => XsdKind::DateTimeStamp,
+            "time" => XsdKind::Time,
+            "gYear" => XsdKind::GYear,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    /// The smallest kind that values of both kinds can be cast to. Numbers are promoted as in
+    /// XPath (integer < decimal < float < double), except that integers and decimals mixed with
+    /// floats become doubles, as a float's 24-bit mantissa would corrupt most IDs. Dates are
+    /// promoted to dateTimes at midnight.
+    fn join(self, other: Self) -> Option<Self> {
+        use XsdKind::*;
+
+        if self == other {
+            return Some(self);
+        }
+        let kind = match (self, other) {
+            (
+                Int {
+                    bits: bits_a,
+                    signed: signed_a,
+                },
+                Int {
+                    bits: bits_b,
+                    signed: signed_b,
+                },
+            ) => {
+                if signed_a == signed_b {
+                    Int {
+                        bits: bits_a.max(bits_b),
+                        signed: signed_a,
+                    }
+                } else {
+                    // A signed type needs twice the width of an unsigned one to hold its values
+                    let (signed_bits, unsigned_bits) = if signed_a {
+                        (bits_a, bits_b)
+                    } else {
+                        (bits_b, bits_a)
+                    };
+                    Int {
+                        bits: signed_bits.max(unsigned_bits * 2),
+                        signed: true,
+                    }
+                }
+            }
+            (Int { .. } | Decimal, Int { .. } | Decimal) => Decimal,
+            (Int { .. } | Decimal | Float | Double, Int { .. } | Decimal | Float | Double) => {
+                Double
+            }
+            (Date | DateTime, Date | DateTime) => DateTime,
+            (Date | DateTime | DateTimeStamp, Date | DateTime | DateTimeStamp) => DateTimeStamp,
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+/// What the lexical forms of the values seen so far require from the column type
+#[derive(Debug, Default, Clone, Copy)]
+struct LexicalShape {
+    /// Largest number of digits before the decimal point of an integer or decimal
+    integer_digits: usize,
+    /// Largest number of digits after the decimal point of a decimal
+    scale: usize,
+    /// Whether any temporal value has a timezone
+    timezone: bool,
+}
+
+impl LexicalShape {
+    fn observe(&mut self, kind: XsdKind, value: &str) {
+        match kind {
+            XsdKind::Int { .. } | XsdKind::Decimal => {
+                let digits = value.trim_start_matches(['+', '-']);
+                let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+                let integer = integer.trim_start_matches('0');
+                let fraction = fraction.trim_end_matches('0');
+                self.integer_digits = self.integer_digits.max(integer.len());
+                self.scale = self.scale.max(fraction.len());
+            }
+            XsdKind::Date
+            | XsdKind::DateTime
+            | XsdKind::DateTimeStamp
+            | XsdKind::Time
+            | XsdKind::GYear => {
+                self.timezone |= has_timezone(value);
+            }
+            XsdKind::Float | XsdKind::Double | XsdKind::Boolean => {}
+        }
+    }
 }
 
-#[derive(serde::Deserialize, Debug)]
-pub struct Head {
-    #[allow(dead_code)]
-    pub vars: Vec<String>,
+/// Whether a lexical form of an XSD date/time type ends with `Z` or `[+-]hh:mm`
+fn has_timezone(value: &str) -> bool {
+    if value.ends_with('Z') {
+        return true;
+    }
+    let bytes = value.as_bytes();
+    bytes.len() > 6
+        && matches!(bytes[bytes.len() - 6], b'+' | b'-')
+        && bytes[bytes.len() - 3] == b':'
 }
 
-#[derive(serde::Deserialize, Debug)]
-pub struct Results {
-    #[serde(default)]
-    pub bindings: Vec<Binding>,
+/// The datatypes of the typed literals of a variable
+#[derive(Debug, Default)]
+struct ObservedTypes {
+    /// Number of values of each datatype
+    counts: BTreeMap<String, usize>,
+    shape: LexicalShape,
 }
 
-#[derive(serde::Deserialize, Debug)]
-pub struct Binding {
-    #[serde(flatten)]
-    pub values: std::collections::HashMap<String, Value>,
+impl ObservedTypes {
+    /// The kind all values can be cast to, if every datatype is in the lattice
+    fn widened_kind(&self) -> Option<XsdKind> {
+        let mut kinds = self.counts.keys().map(|datatype| XsdKind::of(datatype));
+        let first = kinds.next()??;
+        kinds.try_fold(first, |widened, kind| widened.join(kind?))
+    }
 }
 
-#[derive(serde::Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-#[allow(dead_code)]
-pub struct Value {
-    #[serde(rename = "type")]
-    pub value_type: String,
-    #[serde(rename = "xml:lang")]
-    pub xml_lang: Option<String>,
-    pub datatype: Option<String>,
-    pub value: String,
+/// Tracks, per variable, the datatypes of the typed literals seen so far
+#[derive(Default)]
+struct TypeInference {
+    types: HashMap<String, ObservedTypes>,
 }
 
+impl TypeInference {
+    fn observe(&mut self, binding: &Binding) {
+        for (k, v) in binding.values.iter() {
+            if let Some(t) = &v.datatype {
+                let observed = match self.types.get_mut(k) {
+                    Some(observed) => observed,
+                    None => self.types.entry(k.clone()).or_default(),
+                };
+                match observed.counts.get_mut(t) {
+                    Some(count) => *count += 1,
+                    None => {
+                        observed.counts.insert(t.clone(), 1);
+                    }
+                }
+                if let Some(kind) = XsdKind::of(t) {
+                    observed.shape.observe(kind, &v.value);
+                }
+            }
+        }
+    }
+}
+
+/// Values whose datatype was widened to the type of their column
 #[derive(Debug)]
-enum InferredResult {
-    Inconsistent,
-    Consistent { value_type: String },
+pub struct Coercion {
+    pub column: String,
+    pub datatype: String,
+    pub column_type: String,
+    pub count: usize,
 }
 
-pub fn find_consistent_types<S: AsRef<Path>>(
-    src_paths: &[S],
-) -> anyhow::Result<HashMap<String, String>> {
-    let mut types: HashMap<String, InferredResult> = HashMap::new();
+/// Suffixes of the columns keeping the kind, language tag and datatype of each value
+const TERM_SUFFIXES: [&str; 3] = ["__kind", "__lang", "__datatype"];
 
-    for src_path in src_paths {
-        let file = std::fs::File::open(src_path)?;
-        let reader = std::io::BufReader::new(file);
-        let sparql_results: SparqlResults = serde_json::from_reader(reader)?;
-
-        for binding in &sparql_results.results.bindings {
-            for (k, v) in binding.values.iter() {
-                if let Some(t) = &v.datatype {
-                    match types.get(k) {
-                        None => {
-                            types.insert(
-                                k.clone(),
-                                InferredResult::Consistent {
-                                    value_type: t.clone(),
-                                },
-                            );
-                        }
-                        Some(InferredResult::Consistent { value_type }) => {
-                            if value_type != t {
-                                types.insert(k.clone(), InferredResult::Inconsistent);
-                            }
-                        }
-                        Some(InferredResult::Inconsistent) => {}
-                    };
+fn term_column(var: &str, suffix: &str) -> String {
+    escape_sql_identifier(&format!("{}{}", var, suffix))
+}
+
+/// Reads the variables of the results and infers the column types, without keeping the
+/// bindings
+#[derive(Default)]
+struct TypeScanner {
+    vars: Vec<String>,
+    types: TypeInference,
+    /// Whether the RDF term of each value is kept in `TERM_SUFFIXES` columns
+    keep_terms: bool,
+}
+
+impl BindingHandler for TypeScanner {
+    fn head(&mut self, vars: &[String]) -> anyhow::Result<()> {
+        if !self.vars.is_empty() {
+            // Later pages come from the same query, so their variables are expected to match
+            if let Some(var) = vars.iter().find(|var| !self.vars.contains(var)) {
+                return Err(anyhow::anyhow!(
+                    "Variable ?{} is not present in the first result",
+                    var
+                ));
+            }
+            return Ok(());
+        }
+        if vars.is_empty() {
+            return Err(anyhow::anyhow!("SPARQL results have no variables"));
+        }
+
+        if self.keep_terms {
+            for var in vars {
+                for suffix in TERM_SUFFIXES {
+                    if vars.contains(&format!("{}{}", var, suffix)) {
+                        return Err(anyhow::anyhow!(
+                            "Variable ?{}{} clashes with the term details of ?{}",
+                            var,
+                            suffix,
+                            var
+                        ));
+                    }
                 }
             }
         }
+        self.vars = vars.to_vec();
+        Ok(())
     }
 
-    let mut consistent_types: HashMap<String, String> = HashMap::new();
-    for (k, v) in types {
-        if let InferredResult::Consistent { value_type } = v {
-            consistent_types.insert(k, value_type);
+    fn binding(&mut self, binding: Binding) -> anyhow::Result<()> {
+        if self.vars.is_empty() {
+            return Err(anyhow::anyhow!(
+                "SPARQL results have bindings before the head"
+            ));
         }
+        self.types.observe(&binding);
+        Ok(())
     }
+}
 
-    Ok(consistent_types)
+/// The column that the values of a variable are appended to
+#[derive(Debug)]
+struct Column {
+    var: String,
+    sql_type: String,
+    /// Whether the values have a timezone that DuckDB cannot read into the column type
+    drop_timezone: bool,
 }
 
-fn xsd_type_to_duckdb_type(xml_type: &str) -> &str {
-    match xml_type {
-        "http://www.w3.org/2001/XMLSchema#integer" => "INT64",
-        // NOTE xsd:decimal is difficult to convert, because the precision is not known
-        "http://www.w3.org/2001/XMLSchema#double" => "DOUBLE",
-        "http://www.w3.org/2001/XMLSchema#boolean" => "BOOLEAN",
-        _ => "VARCHAR",
+/// Drops the timezone of a date or a year, which does not change which day or year is meant
+fn without_timezone(value: &str) -> &str {
+    if let Some(value) = value.strip_suffix('Z') {
+        value
+    } else if has_timezone(value) {
+        &value[..value.len() - 6]
+    } else {
+        value
     }
 }
 
-fn read_json_columns_struct(vars: &[String], consistent_types: &HashMap<String, String>) -> String {
-    let columns = vars
-        .iter()
-        .map(|var| {
-            let t = consistent_types
-                .get(var)
-                .map(|t| xsd_type_to_duckdb_type(t))
-                .unwrap_or("VARCHAR");
+/// Appends each binding as a row of the table, letting DuckDB cast the lexical form of each
+/// value to the type of its column
+struct RowAppender<'conn, 'a> {
+    conn: &'conn Connection,
+    columns: &'a [Column],
+    appender: Appender<'conn>,
+    /// Whether to append the RDF term of each value to `TERM_SUFFIXES` columns
+    keep_terms: bool,
+}
+
+impl RowAppender<'_, '_> {
+    /// The value that DuckDB failed to cast, as its error names neither the column nor the
+    /// value
+    fn cast_error(&self, row: &[Option<String>]) -> Option<anyhow::Error> {
+        let stride = row.len() / self.columns.len();
+        self.columns
+            .iter()
+            .zip(row.iter().step_by(stride))
+            .find_map(|(column, value)| {
+                let value = value.as_ref()?;
+                let failed: bool = self
+                    .conn
+                    .query_row(
+                        &format!("SELECT TRY_CAST(?::VARCHAR AS {}) IS NULL", column.sql_type),
+                        [value],
+                        |row| row.get(0),
+                    )
+                    .ok()?;
+                failed.then(|| {
+                    anyhow::anyhow!(
+                        "Value {:?} of ?{} cannot be cast to {}",
+                        value,
+                        column.var,
+                        column.sql_type
+                    )
+                })
+            })
+    }
+}
+
+impl BindingHandler for RowAppender<'_, '_> {
+    fn head(&mut self, _vars: &[String]) -> anyhow::Result<()> {
+        // The variables were checked while inferring the column types
+        Ok(())
+    }
+
+    fn binding(&mut self, mut binding: Binding) -> anyhow::Result<()> {
+        let mut row = vec![];
+        for column in self.columns {
+            match binding.values.remove(&column.var) {
+                Some(v) => {
+                    let value = if column.drop_timezone {
+                        without_timezone(&v.value).to_string()
+                    } else {
+                        v.value
+                    };
+                    row.push(Some(value));
+                    if self.keep_terms {
+                        row.extend([Some(v.value_type), v.xml_lang, v.datatype]);
+                    }
+                }
+                None => {
+                    row.push(None);
+                    if self.keep_terms {
+                        row.extend([None, None, None]);
+                    }
+                }
+            }
+        }
+        if let Err(error) = self.appender.append_row(appender_params_from_iter(&row)) {
+            return Err(self.cast_error(&row).unwrap_or_else(|| error.into()));
+        }
+        Ok(())
+    }
+}
+
+/// Widest DECIMAL supported by DuckDB
+const MAX_DECIMAL_WIDTH: usize = 38;
+
+/// Most digits of an integer that always fits in a BIGINT
+const BIGINT_DIGITS: usize = 18;
+/// Most digits of an integer that always fits in a HUGEINT
+const HUGEINT_DIGITS: usize = 38;
+
+fn xsd_type_to_duckdb_type(kind: XsdKind, shape: &LexicalShape) -> String {
+    let duckdb_type = match kind {
+        XsdKind::Int { signed: true, .. } if shape.integer_digits > HUGEINT_DIGITS => "DOUBLE",
+        XsdKind::Int { bits: 128, .. } => "HUGEINT",
+        XsdKind::Int {
+            bits: 64,
+            signed: true,
+        } if shape.integer_digits > BIGINT_DIGITS => "HUGEINT",
+        XsdKind::Int { bits, signed: true } => match bits {
+            8 => "TINYINT",
+            16 => "SMALLINT",
+            32 => "INTEGER",
+            _ => "BIGINT",
+        },
+        XsdKind::Int {
+            bits,
+            signed: false,
+        } => match bits {
+            8 => "UTINYINT",
+            16 => "USMALLINT",
+            32 => "UINTEGER",
+            _ => "UBIGINT",
+        },
+        XsdKind::Decimal => {
+            let width = (shape.integer_digits + shape.scale).max(1);
+            if width > MAX_DECIMAL_WIDTH {
+                // Rounding the fraction keeps the integer part exact as long as it fits
+                if shape.integer_digits > MAX_DECIMAL_WIDTH {
+                    return "DOUBLE".to_string();
+                }
+                return format!(
+                    "DECIMAL({}, {})",
+                    MAX_DECIMAL_WIDTH,
+                    MAX_DECIMAL_WIDTH - shape.integer_digits
+                );
+            }
+            return format!("DECIMAL({}, {})", width, shape.scale);
+        }
+        XsdKind::Double => "DOUBLE",
+        XsdKind::Float => "FLOAT",
+        XsdKind::Boolean => "BOOLEAN",
+        // A naive dateTime in a column with timezones is appended as UTC
+        XsdKind::DateTime if shape.timezone => "TIMESTAMPTZ",
+        XsdKind::DateTime => "TIMESTAMP",
+        XsdKind::DateTimeStamp => "TIMESTAMPTZ",
+        // The timezone of a date is dropped, since it does not change which day is meant
+        XsdKind::Date => "DATE",
+        XsdKind::GYear => "INTEGER",
+        XsdKind::Time if !shape.timezone => "TIME",
+        XsdKind::Time => "VARCHAR",
+    };
+    duckdb_type.to_string()
+}
+
+/// The column of each variable with its widened type, and the values whose datatype differs
+/// from that type
+fn infer_columns(
+    vars: &[String],
+    types: &HashMap<String, ObservedTypes>,
+    qm: &QueryMetadata,
+) -> (Vec<Column>, Vec<Coercion>) {
+    let mut coercions = vec![];
+    let mut columns = vec![];
+    for var in vars {
+        if let Some(column_type) = qm.column_types.iter().find(|t| &t.variable == var) {
+            columns.push(Column {
+                var: var.clone(),
+                sql_type: column_type.sql_type.clone(),
+                drop_timezone: false,
+            });
+            continue;
+        }
 
-            format!("{}: {}", escape_sql_literal(var), escape_sql_literal(t))
-        })
-        .collect::<Vec<String>>()
-        .join(", ");
-    format!("{{{}}}", columns)
+        let observed = types.get(var);
+        let kind = observed.and_then(ObservedTypes::widened_kind);
+        let sql_type = match (observed, kind) {
+            (Some(observed), Some(kind)) => {
+                let t = xsd_type_to_duckdb_type(kind, &observed.shape);
+                for (datatype, count) in &observed.counts {
+                    if XsdKind::of(datatype) != Some(kind) {
+                        coercions.push(Coercion {
+                            column: var.clone(),
+                            datatype: datatype.clone(),
+                            column_type: t.clone(),
+                            count: *count,
+                        });
+                    }
+                }
+                t
+            }
+            _ => "VARCHAR".to_string(),
+        };
+        columns.push(Column {
+            var: var.clone(),
+            sql_type,
+            drop_timezone: matches!(kind, Some(XsdKind::Date | XsdKind::GYear)),
+        });
+    }
+    (columns, coercions)
+}
+
+fn create_table(
+    conn: &Connection,
+    table: &str,
+    temp: bool,
+    columns: &[Column],
+    keep_terms: bool,
+) -> anyhow::Result<()> {
+    let mut definitions = vec![];
+    for column in columns {
+        definitions.push(format!(
+            "{} {}",
+            escape_sql_identifier(&column.var),
+            column.sql_type
+        ));
+        if keep_terms {
+            for suffix in TERM_SUFFIXES {
+                definitions.push(format!("{} VARCHAR", term_column(&column.var, suffix)));
+            }
+        }
+    }
+    let query = format!(
+        "CREATE OR REPLACE {}TABLE {} ({})",
+        if temp { "TEMP " } else { "" },
+        escape_sql_identifier(table),
+        definitions.join(", ")
+    );
+    log::debug!("Executing query:\n{}", query);
+    conn.execute(&query, [])?;
+    Ok(())
+}
+
+/// The SELECT list turning the appended columns into the structs of `# Terms: struct` and
+/// the columns of `# PivotLanguages`
+fn select_reshaped_columns(columns: &[Column], qm: &QueryMetadata) -> String {
+    let mut select = vec![];
+    for Column { var, .. } in columns {
+        let column = escape_sql_identifier(var);
+        if let Some(pivot) = qm.language_pivots.iter().find(|p| &p.variable == var) {
+            // Aggregated over the other columns, which are grouped by
+            for language in &pivot.languages {
+                select.push(format!(
+                    "first({}) FILTER (WHERE lower({}) = {}) AS {}",
+                    column,
+                    term_column(var, "__lang"),
+                    escape_sql_literal(language),
+                    escape_sql_identifier(&format!("{}_{}", var, language.replace('-', "_")))
+                ));
+            }
+            continue;
+        }
+
+        match qm.terms {
+            None => select.push(column),
+            Some(TermDetails::Columns) => {
+                select.push(column);
+                for suffix in TERM_SUFFIXES {
+                    select.push(term_column(var, suffix));
+                }
+            }
+            Some(TermDetails::Struct) => select.push(format!(
+                "CASE WHEN {column} IS NULL THEN NULL ELSE struct_pack(value := {column}, kind := {kind}, lang := {lang}, datatype := {datatype}) END AS {column}",
+                column = column,
+                kind = term_column(var, "__kind"),
+                lang = term_column(var, "__lang"),
+                datatype = term_column(var, "__datatype"),
+            )),
+        }
+    }
+    select.join(", ")
 }
 
+/// Loads SPARQL results into a table, returning the values that were widened to the type of
+/// their column. The column types are inferred in a first pass over the results, and the
+/// values are appended to the table with those types in a second one.
 pub fn sparql_results_to_duckdb<S: AsRef<Path>>(
     conn: &Connection,
     base_name: &str,
     src_paths: &[S],
-) -> anyhow::Result<()> {
-    let mut temp_jsons = tempfile::NamedTempFile::new()?;
-
-    let pb = ProgressBar::new(src_paths.len() as u64);
+    qm: &QueryMetadata,
+    progress: &MultiProgress,
+) -> anyhow::Result<Vec<Coercion>> {
+    let pb = progress.add(ProgressBar::new(2 * src_paths.len() as u64));
     pb.set_style(ProgressStyle::with_template(
         "{spinner:.green} [{elapsed_precise}] {bar:20} {pos:>3}/{len:3} ({eta}) {msg}",
     )?);
 
-    let consistent_types = find_consistent_types(src_paths)?;
-    log::debug!("Consistent types: {:?}", consistent_types);
-
-    let mut vars = vec![];
+    let keep_terms = qm.terms.is_some() || !qm.language_pivots.is_empty();
+    let mut scanner = TypeScanner {
+        keep_terms,
+        ..Default::default()
+    };
+    pb.set_message("Inferring column types");
     for src_path in src_paths {
-        pb.set_message("Parsing SPARQL Result JSON");
-        let file = std::fs::File::open(src_path)?;
-        let reader = std::io::BufReader::new(file);
-        let sparql_results: SparqlResults = serde_json::from_reader(reader)?;
-        vars = sparql_results.head.vars.clone();
-
-        pb.set_message("Writing JSONs");
-        for binding in &sparql_results.results.bindings {
-            let row: serde_json::Map<String, serde_json::Value> = binding
-                .values
-                .iter()
-                .map(|(k, v)| (k.clone(), serde_json::Value::String(v.value.clone())))
-                .collect();
-            let row = serde_json::Value::Object(row);
-            let row = serde_json::to_string(&row)?;
-            temp_jsons.write_all(row.as_bytes())?;
-        }
+        read_sparql_results_file(src_path, qm.format, &mut scanner)?;
         pb.inc(1);
     }
-    temp_jsons.flush()?;
+    let TypeScanner { vars, types, .. } = scanner;
+    if vars.is_empty() {
+        return Err(anyhow::anyhow!("No SPARQL results to load"));
+    }
 
-    pb.finish_with_message("Written intermediate JSONs");
-    pb.set_style(ProgressStyle::with_template(
-        "{spinner:.green} [{elapsed_precise}] {msg}",
-    )?);
+    let types = types.types;
+    log::debug!("Observed types: {:?}", types);
+    if let Some(column_type) = qm.column_types.iter().find(|t| !vars.contains(&t.variable)) {
+        return Err(anyhow::anyhow!(
+            "Type is given for ?{}, which is not a variable of the results",
+            column_type.variable
+        ));
+    }
+    if let Some(pivot) = qm
+        .language_pivots
+        .iter()
+        .find(|p| !vars.contains(&p.variable))
+    {
+        return Err(anyhow::anyhow!(
+            "PivotLanguages is given for ?{}, which is not a variable of the results",
+            pivot.variable
+        ));
+    }
+    let (columns, coercions) = infer_columns(&vars, &types, qm);
 
-    let columns = read_json_columns_struct(&vars, &consistent_types);
+    // Structs and pivoted languages are built with SQL from a temporary table; otherwise the
+    // bindings are appended to the table itself
+    let reshape = matches!(qm.terms, Some(TermDetails::Struct)) || !qm.language_pivots.is_empty();
+    let table = if reshape {
+        format!("tabulae_terms_{}", base_name)
+    } else {
+        base_name.to_string()
+    };
 
-    let jsons_path = temp_jsons.path().to_str().unwrap_or_default();
-    pb.set_message("Loading JSONs to DuckDB");
-    let query = format!(
-        "CREATE OR REPLACE TABLE {} AS FROM read_json({}, columns = {})",
-        escape_sql_identifier(base_name),
-        escape_sql_literal(jsons_path),
-        columns
-    );
-    log::debug!("Executing query:\n{}", query);
-    conn.execute(&query, params![])?;
+    // A failed load leaves the table of the previous build in place
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let result = (|| -> anyhow::Result<()> {
+        create_table(conn, &table, reshape, &columns, keep_terms)?;
+        let mut handler = RowAppender {
+            conn,
+            columns: &columns,
+            appender: conn.appender(&table)?,
+            keep_terms,
+        };
+        pb.set_message("Appending bindings");
+        for src_path in src_paths {
+            read_sparql_results_file(src_path, qm.format, &mut handler)?;
+            pb.inc(1);
+        }
+        handler.appender.flush()?;
+        drop(handler);
+        pb.finish_with_message("Appended bindings");
+
+        if reshape {
+            pb.set_style(ProgressStyle::with_template(
+                "{spinner:.green} [{elapsed_precise}] {msg}",
+            )?);
+            pb.set_message("Building the term details");
+            let mut query = format!(
+                "CREATE OR REPLACE TABLE {} AS SELECT {} FROM temp.{}",
+                escape_sql_identifier(base_name),
+                select_reshaped_columns(&columns, qm),
+                escape_sql_identifier(&table)
+            );
+            if !qm.language_pivots.is_empty() {
+                // One row per combination of the other columns
+                query.push_str(" GROUP BY ALL");
+            }
+            log::debug!("Executing query:\n{}", query);
+            conn.execute(&query, params![])?;
+            conn.execute(
+                &format!("DROP TABLE temp.{}", escape_sql_identifier(&table)),
+                params![],
+            )?;
+        }
+        Ok(())
+    })();
+    if let Err(error) = result {
+        conn.execute_batch("ROLLBACK")?;
+        return Err(error);
+    }
+    conn.execute_batch("COMMIT")?;
     pb.finish_with_message("Done");
 
-    Ok(())
+    Ok(coercions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn int(bits: u8, signed: bool) -> XsdKind {
+        XsdKind::Int { bits, signed }
+    }
+
+    #[test]
+    fn join_integers() {
+        assert_eq!(int(32, true).join(int(64, true)), Some(int(64, true)));
+        assert_eq!(int(8, false).join(int(16, false)), Some(int(16, false)));
+        assert_eq!(int(32, true).join(int(8, false)), Some(int(32, true)));
+        assert_eq!(int(8, true).join(int(32, false)), Some(int(64, true)));
+        assert_eq!(int(32, true).join(int(64, false)), Some(int(128, true)));
+    }
+
+    #[test]
+    fn join_numbers_and_times() {
+        use XsdKind::*;
+
+        assert_eq!(int(64, false).join(Decimal), Some(Decimal));
+        assert_eq!(Decimal.join(Float), Some(Double));
+        assert_eq!(Float.join(int(8, true)), Some(Double));
+        assert_eq!(int(64, true).join(Float), Some(Double));
+        assert_eq!(Double.join(Decimal), Some(Double));
+        assert_eq!(Float.join(Double), Some(Double));
+        assert_eq!(Date.join(DateTime), Some(DateTime));
+        assert_eq!(DateTimeStamp.join(Date), Some(DateTimeStamp));
+        assert_eq!(Date.join(int(64, true)), None);
+        assert_eq!(Boolean.join(int(8, false)), None);
+        assert_eq!(Time.join(DateTime), None);
+        assert_eq!(GYear.join(Date), None);
+    }
+
+    #[test]
+    fn join_is_commutative() {
+        use XsdKind::*;
+
+        let kinds = [
+            int(8, true),
+            int(16, false),
+            int(32, true),
+            int(64, false),
+            Decimal,
+            Float,
+            Double,
+            Boolean,
+            Date,
+            DateTime,
+            DateTimeStamp,
+            Time,
+            GYear,
+        ];
+        for a in kinds {
+            for b in kinds {
+                assert_eq!(a.join(b), b.join(a), "{:?} and {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn widened_kind_of_observed_types() {
+        let observed = |datatypes: &[&str]| ObservedTypes {
+            counts: datatypes
+                .iter()
+                .map(|datatype| (format!("{}{}", XSD, datatype), 1))
+                .collect(),
+            shape: LexicalShape::default(),
+        };
+
+        assert_eq!(
+            observed(&["int", "unsignedLong"]).widened_kind(),
+            Some(int(128, true))
+        );
+        assert_eq!(
+            observed(&["integer", "decimal", "double"]).widened_kind(),
+            Some(XsdKind::Double)
+        );
+        assert_eq!(observed(&["date", "integer"]).widened_kind(), None);
+        assert_eq!(observed(&["string"]).widened_kind(), None);
+        assert_eq!(observed(&[]).widened_kind(), None);
+    }
+
+    #[test]
+    fn timezones_are_dropped() {
+        assert_eq!(without_timezone("2020-01-01Z"), "2020-01-01");
+        assert_eq!(without_timezone("2020-01-01+09:00"), "2020-01-01");
+        assert_eq!(without_timezone("-0044-05:00"), "-0044");
+        assert_eq!(without_timezone("2020-01-01"), "2020-01-01");
+        assert_eq!(without_timezone("2020"), "2020");
+    }
+
+    #[test]
+    fn integer_type_follows_the_digits() {
+        let duckdb_type = |values: &[&str]| {
+            let mut shape = LexicalShape::default();
+            for value in values {
+                shape.observe(int(64, true), value);
+            }
+            xsd_type_to_duckdb_type(int(64, true), &shape)
+        };
+
+        assert_eq!(duckdb_type(&["1", "-999999999999999999"]), "BIGINT");
+        assert_eq!(duckdb_type(&["0009223372036854775807"]), "HUGEINT");
+        assert_eq!(duckdb_type(&["-12345678901234567890123456789"]), "HUGEINT");
+        assert_eq!(duckdb_type(&["9".repeat(38).as_str()]), "HUGEINT");
+        assert_eq!(duckdb_type(&["1".repeat(39).as_str()]), "DOUBLE");
+    }
 }
@@ -0,0 +1,806 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufRead, Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{
+    Deserializer,
+    de::{DeserializeSeed, Error as _, IgnoredAny, MapAccess, SeqAccess, Visitor},
+};
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Head {
+    #[serde(default)]
+    pub vars: Vec<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+#[serde(transparent)]
+pub struct Binding {
+    pub values: HashMap<String, Value>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct Value {
+    #[serde(rename = "type")]
+    pub value_type: String,
+    #[serde(rename = "xml:lang", skip_serializing_if = "Option::is_none")]
+    pub xml_lang: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub datatype: Option<String>,
+    pub value: String,
+}
+
+/// Receives the parts of a SPARQL result document as they are parsed
+pub trait BindingHandler {
+    fn head(&mut self, vars: &[String]) -> anyhow::Result<()>;
+    fn binding(&mut self, binding: Binding) -> anyhow::Result<()>;
+}
+
+/// Counts the bindings without keeping them
+#[derive(Default)]
+pub struct CountBindings(pub usize);
+
+impl BindingHandler for CountBindings {
+    fn head(&mut self, _vars: &[String]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn binding(&mut self, _binding: Binding) -> anyhow::Result<()> {
+        self.0 += 1;
+        Ok(())
+    }
+}
+
+/// Keeps only the last binding, e.g. to continue keyset pagination after a page
+#[derive(Default)]
+pub struct LastBinding(pub Option<Binding>);
+
+impl BindingHandler for LastBinding {
+    fn head(&mut self, _vars: &[String]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn binding(&mut self, binding: Binding) -> anyhow::Result<()> {
+        self.0 = Some(binding);
+        Ok(())
+    }
+}
+
+/// Serialization of SPARQL SELECT results requested from the endpoint
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResultFormat {
+    #[default]
+    Json,
+    Xml,
+    Tsv,
+    Csv,
+}
+
+impl ResultFormat {
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            ResultFormat::Json => "application/sparql-results+json",
+            ResultFormat::Xml => "application/sparql-results+xml",
+            ResultFormat::Tsv => "text/tab-separated-values",
+            ResultFormat::Csv => "text/csv",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ResultFormat::Json => "json",
+            ResultFormat::Xml => "xml",
+            ResultFormat::Tsv => "tsv",
+            ResultFormat::Csv => "csv",
+        }
+    }
+}
+
+impl FromStr for ResultFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "json" | "srj" | "application/sparql-results+json" => Ok(ResultFormat::Json),
+            "xml" | "srx" | "application/sparql-results+xml" => Ok(ResultFormat::Xml),
+            "tsv" | "text/tab-separated-values" => Ok(ResultFormat::Tsv),
+            "csv" | "text/csv" => Ok(ResultFormat::Csv),
+            _ => Err(anyhow::anyhow!("Unsupported result format: {}", s)),
+        }
+    }
+}
+
+/// Parses a SPARQL result document in the given format, handing over one binding at a time
+pub fn read_sparql_results<R: BufRead, H: BindingHandler>(
+    reader: R,
+    format: ResultFormat,
+    handler: &mut H,
+) -> anyhow::Result<()> {
+    match format {
+        ResultFormat::Json => read_sparql_results_json(reader, handler),
+        ResultFormat::Xml => read_sparql_results_xml(reader, handler),
+        ResultFormat::Tsv => read_sparql_results_tsv(reader, handler),
+        ResultFormat::Csv => read_sparql_results_csv(reader, handler),
+    }
+}
+
+pub fn read_sparql_results_file<P: AsRef<Path>, H: BindingHandler>(
+    path: P,
+    format: ResultFormat,
+    handler: &mut H,
+) -> anyhow::Result<()> {
+    let file = std::fs::File::open(&path)?;
+    let reader = std::io::BufReader::new(file);
+    read_sparql_results(reader, format, handler)
+        .map_err(|e| e.context(format!("Failed to parse {}", path.as_ref().display())))
+}
+
+/// Parses a `application/sparql-results+json` document so that memory use doesn't depend on
+/// the number of bindings
+pub fn read_sparql_results_json<R: Read, H: BindingHandler>(
+    reader: R,
+    handler: &mut H,
+) -> anyhow::Result<()> {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    de.deserialize_map(DocumentVisitor { handler })?;
+    de.end()?;
+    Ok(())
+}
+
+struct DocumentVisitor<'a, H> {
+    handler: &'a mut H,
+}
+
+impl<'de, H: BindingHandler> Visitor<'de> for DocumentVisitor<'_, H> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a SPARQL results JSON object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        // JSON members are unordered, so bindings before the head are kept until it is read
+        let mut head_seen = false;
+        let mut early_bindings: Option<EarlyBindings> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "head" => {
+                    let head: Head = map.next_value()?;
+                    self.handler.head(&head.vars).map_err(A::Error::custom)?;
+                    head_seen = true;
+                    if let Some(early_bindings) = early_bindings.take() {
+                        early_bindings
+                            .replay(&mut *self.handler)
+                            .map_err(A::Error::custom)?;
+                    }
+                }
+                "results" if head_seen => map.next_value_seed(ResultsSeed {
+                    handler: &mut *self.handler,
+                })?,
+                "results" => {
+                    let mut buffer = EarlyBindings::new().map_err(A::Error::custom)?;
+                    map.next_value_seed(ResultsSeed {
+                        handler: &mut buffer,
+                    })?;
+                    early_bindings = Some(buffer);
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        // Without a head, the variables are those bound in the results
+        if let Some(early_bindings) = early_bindings {
+            self.handler
+                .head(&early_bindings.vars)
+                .map_err(A::Error::custom)?;
+            early_bindings
+                .replay(&mut *self.handler)
+                .map_err(A::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bindings read before the head, spilled to a temporary file as JSON lines so that memory use
+/// stays bounded
+struct EarlyBindings {
+    writer: std::io::BufWriter<std::fs::File>,
+    /// Variables in the order they are first bound
+    vars: Vec<String>,
+}
+
+impl EarlyBindings {
+    fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            writer: std::io::BufWriter::new(tempfile::tempfile()?),
+            vars: vec![],
+        })
+    }
+
+    fn replay<H: BindingHandler>(self, handler: &mut H) -> anyhow::Result<()> {
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        for line in std::io::BufReader::new(file).lines() {
+            handler.binding(serde_json::from_str(&line?)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl BindingHandler for EarlyBindings {
+    fn head(&mut self, _vars: &[String]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn binding(&mut self, binding: Binding) -> anyhow::Result<()> {
+        let mut new_vars = binding
+            .values
+            .keys()
+            .filter(|var| !self.vars.contains(var))
+            .cloned()
+            .collect::<Vec<String>>();
+        new_vars.sort();
+        self.vars.extend(new_vars);
+        serde_json::to_writer(&mut self.writer, &binding)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+struct ResultsSeed<'a, H> {
+    handler: &'a mut H,
+}
+
+impl<'de, H: BindingHandler> DeserializeSeed<'de> for ResultsSeed<'_, H> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, H: BindingHandler> Visitor<'de> for ResultsSeed<'_, H> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a results object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "bindings" {
+                map.next_value_seed(BindingsSeed {
+                    handler: &mut *self.handler,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct BindingsSeed<'a, H> {
+    handler: &'a mut H,
+}
+
+impl<'de, H: BindingHandler> DeserializeSeed<'de> for BindingsSeed<'_, H> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, H: BindingHandler> Visitor<'de> for BindingsSeed<'_, H> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of bindings")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(binding) = seq.next_element::<Binding>()? {
+            self.handler.binding(binding).map_err(A::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+const XSD_INTEGER: &str = "http://www.w3.org/2001/XMLSchema#integer";
+const XSD_DECIMAL: &str = "http://www.w3.org/2001/XMLSchema#decimal";
+const XSD_DOUBLE: &str = "http://www.w3.org/2001/XMLSchema#double";
+const XSD_BOOLEAN: &str = "http://www.w3.org/2001/XMLSchema#boolean";
+
+/// Parses a `text/tab-separated-values` document, whose values are RDF terms in Turtle syntax
+pub fn read_sparql_results_tsv<R: BufRead, H: BindingHandler>(
+    reader: R,
+    handler: &mut H,
+) -> anyhow::Result<()> {
+    let mut lines = reader.lines();
+    let header = match lines.next() {
+        Some(header) => header?,
+        None => return Err(anyhow::anyhow!("Missing TSV header")),
+    };
+    let vars = header
+        .trim_end_matches('\r')
+        .split('\t')
+        .map(|field| match field.strip_prefix(['?', '$']) {
+            Some(var) if !var.is_empty() => Ok(var.to_string()),
+            _ => Err(anyhow::anyhow!(
+                "Invalid TSV header field {:?}: variables must start with ? or $",
+                field
+            )),
+        })
+        .collect::<anyhow::Result<Vec<String>>>()?;
+    handler.head(&vars)?;
+
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let mut values = HashMap::new();
+        for (var, field) in vars.iter().zip(line.split('\t')) {
+            let term = parse_tsv_term(field)
+                .map_err(|e| e.context(format!("Invalid TSV value on line {}", i + 2)))?;
+            if let Some(term) = term {
+                values.insert(var.clone(), term);
+            }
+        }
+        handler.binding(Binding { values })?;
+    }
+
+    Ok(())
+}
+
+fn parse_tsv_term(field: &str) -> anyhow::Result<Option<Value>> {
+    let term = |value_type: &str, value: String, xml_lang, datatype: Option<&str>| Value {
+        value_type: value_type.to_string(),
+        xml_lang,
+        datatype: datatype.map(|d| d.to_string()),
+        value,
+    };
+
+    if field.is_empty() {
+        return Ok(None);
+    }
+    if let Some(iri) = field.strip_prefix('<').and_then(|f| f.strip_suffix('>')) {
+        return Ok(Some(term("uri", iri.to_string(), None, None)));
+    }
+    if let Some(id) = field.strip_prefix("_:") {
+        return Ok(Some(term("bnode", id.to_string(), None, None)));
+    }
+    if let Some(rest) = field.strip_prefix('"') {
+        let (value, suffix) = unescape_turtle_string(rest)?;
+        if let Some(lang) = suffix.strip_prefix('@') {
+            return Ok(Some(term("literal", value, Some(lang.to_string()), None)));
+        }
+        if let Some(datatype) = suffix.strip_prefix("^^<").and_then(|d| d.strip_suffix('>')) {
+            return Ok(Some(term("literal", value, None, Some(datatype))));
+        }
+        if suffix.is_empty() {
+            return Ok(Some(term("literal", value, None, None)));
+        }
+        return Err(anyhow::anyhow!("Unexpected literal suffix: {}", suffix));
+    }
+
+    // Numbers and booleans may be written without quotes, as in Turtle
+    let datatype = if field == "true" || field == "false" {
+        XSD_BOOLEAN
+    } else if field.parse::<i128>().is_ok() {
+        XSD_INTEGER
+    } else if field.contains(['e', 'E']) && field.parse::<f64>().is_ok() {
+        XSD_DOUBLE
+    } else if field.contains('.') && field.parse::<f64>().is_ok() {
+        XSD_DECIMAL
+    } else {
+        return Err(anyhow::anyhow!("Unsupported RDF term: {}", field));
+    };
+    Ok(Some(term(
+        "literal",
+        field.to_string(),
+        None,
+        Some(datatype),
+    )))
+}
+
+/// Unescapes a Turtle string after its opening quote, returning it with the text that follows
+/// the closing quote
+fn unescape_turtle_string(s: &str) -> anyhow::Result<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[i + 1..])),
+            '\\' => {
+                let (_, escaped) = chars
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Unterminated escape sequence"))?;
+                match escaped {
+                    't' => value.push('\t'),
+                    'b' => value.push('\u{8}'),
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    'f' => value.push('\u{c}'),
+                    '"' | '\'' | '\\' => value.push(escaped),
+                    'u' | 'U' => {
+                        let len = if escaped == 'u' { 4 } else { 8 };
+                        let hex = (0..len)
+                            .map(|_| chars.next().map(|(_, c)| c))
+                            .collect::<Option<String>>()
+                            .ok_or_else(|| anyhow::anyhow!("Truncated unicode escape"))?;
+                        let code = u32::from_str_radix(&hex, 16)?;
+                        value.push(
+                            char::from_u32(code)
+                                .ok_or_else(|| anyhow::anyhow!("Invalid code point: {}", hex))?,
+                        );
+                    }
+                    _ => return Err(anyhow::anyhow!("Unknown escape sequence: \\{}", escaped)),
+                }
+            }
+            _ => value.push(c),
+        }
+    }
+    Err(anyhow::anyhow!("Unterminated string literal"))
+}
+
+/// Parses a `text/csv` document. CSV results carry neither term types nor datatypes, so every
+/// value is read as a plain literal
+pub fn read_sparql_results_csv<R: BufRead, H: BindingHandler>(
+    reader: R,
+    handler: &mut H,
+) -> anyhow::Result<()> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(reader);
+    let vars = reader
+        .headers()?
+        .iter()
+        .map(|var| var.to_string())
+        .collect::<Vec<String>>();
+    handler.head(&vars)?;
+
+    for record in reader.records() {
+        let record = record?;
+        let values = vars
+            .iter()
+            .zip(record.iter())
+            // Unbound values and empty strings cannot be told apart; both become NULL
+            .filter(|(_, field)| !field.is_empty())
+            .map(|(var, field)| {
+                let value = Value {
+                    value_type: "literal".to_string(),
+                    xml_lang: None,
+                    datatype: None,
+                    value: field.to_string(),
+                };
+                (var.clone(), value)
+            })
+            .collect();
+        handler.binding(Binding { values })?;
+    }
+
+    Ok(())
+}
+
+/// Parses a `application/sparql-results+xml` document
+pub fn read_sparql_results_xml<R: BufRead, H: BindingHandler>(
+    reader: R,
+    handler: &mut H,
+) -> anyhow::Result<()> {
+    use quick_xml::events::{BytesStart, Event};
+
+    fn attribute(e: &BytesStart, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(match e.try_get_attribute(name)? {
+            Some(attr) => Some(attr.unescape_value()?.into_owned()),
+            None => None,
+        })
+    }
+
+    let mut reader = quick_xml::Reader::from_reader(reader);
+    let mut buf = vec![];
+
+    let mut vars = vec![];
+    let mut binding: Option<HashMap<String, Value>> = None;
+    let mut binding_name: Option<String> = None;
+    let mut term: Option<Value> = None;
+
+    loop {
+        let event = reader.read_event_into(&mut buf)?;
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                let is_empty = matches!(event, Event::Empty(_));
+                match e.local_name().as_ref() {
+                    b"variable" => {
+                        if let Some(name) = attribute(e, "name")? {
+                            vars.push(name);
+                        }
+                    }
+                    b"results" => handler.head(&vars)?,
+                    b"result" => {
+                        binding = Some(HashMap::new());
+                        if is_empty {
+                            handler.binding(Binding {
+                                values: binding.take().unwrap_or_default(),
+                            })?;
+                        }
+                    }
+                    b"binding" => binding_name = attribute(e, "name")?,
+                    kind @ (b"uri" | b"literal" | b"bnode") => {
+                        term = Some(Value {
+                            value_type: String::from_utf8_lossy(kind).into_owned(),
+                            xml_lang: attribute(e, "xml:lang")?,
+                            datatype: attribute(e, "datatype")?,
+                            value: String::new(),
+                        });
+                    }
+                    _ => {}
+                }
+                if is_empty && term.is_some() {
+                    // `<literal/>` is an empty string
+                    if let (Some(values), Some(name), Some(term)) =
+                        (binding.as_mut(), binding_name.clone(), term.take())
+                    {
+                        values.insert(name, term);
+                    }
+                }
+            }
+            Event::Text(e) => {
+                if let Some(term) = term.as_mut() {
+                    term.value.push_str(&e.unescape()?);
+                }
+            }
+            Event::CData(e) => {
+                if let Some(term) = term.as_mut() {
+                    term.value.push_str(std::str::from_utf8(e)?);
+                }
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"uri" | b"literal" | b"bnode" => {
+                    if let (Some(values), Some(name), Some(term)) =
+                        (binding.as_mut(), binding_name.clone(), term.take())
+                    {
+                        values.insert(name, term);
+                    }
+                }
+                b"binding" => binding_name = None,
+                b"result" => {
+                    if let Some(values) = binding.take() {
+                        handler.binding(Binding { values })?;
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Collect {
+        head_seen: bool,
+        vars: Vec<String>,
+        bindings: Vec<HashMap<String, Value>>,
+    }
+
+    impl BindingHandler for Collect {
+        fn head(&mut self, vars: &[String]) -> anyhow::Result<()> {
+            self.head_seen = true;
+            self.vars = vars.to_vec();
+            Ok(())
+        }
+
+        fn binding(&mut self, binding: Binding) -> anyhow::Result<()> {
+            // As for TypeScanner, the variables must be known before the first binding
+            anyhow::ensure!(self.head_seen, "Binding before the head");
+            self.bindings.push(binding.values);
+            Ok(())
+        }
+    }
+
+    fn term(value: &Value) -> (&str, &str, Option<&str>, Option<&str>) {
+        (
+            &value.value_type,
+            &value.value,
+            value.xml_lang.as_deref(),
+            value.datatype.as_deref(),
+        )
+    }
+
+    const JSON_BINDINGS: &str = r#""results": { "bindings": [
+            { "s": { "type": "uri", "value": "http://example.org/a" },
+              "o": { "type": "literal", "value": "chat", "xml:lang": "fr" } },
+            { "o": { "type": "literal", "value": "42",
+                     "datatype": "http://www.w3.org/2001/XMLSchema#integer" } }
+        ] }"#;
+
+    fn assert_json_bindings(results: &Collect) {
+        let [first, second] = results.bindings.as_slice() else {
+            panic!("Expected 2 bindings, got {}", results.bindings.len());
+        };
+        assert_eq!(
+            term(&first["s"]),
+            ("uri", "http://example.org/a", None, None)
+        );
+        assert_eq!(term(&first["o"]), ("literal", "chat", Some("fr"), None));
+        assert!(!second.contains_key("s"));
+        assert_eq!(
+            term(&second["o"]),
+            ("literal", "42", None, Some(XSD_INTEGER))
+        );
+    }
+
+    #[test]
+    fn json_head_before_results() {
+        let json = format!(
+            r#"{{ "head": {{ "vars": ["s", "o", "unused"] }}, "extra": [1, {{}}], {} }}"#,
+            JSON_BINDINGS
+        );
+        let mut results = Collect::default();
+        read_sparql_results_json(json.as_bytes(), &mut results).unwrap();
+
+        assert_eq!(results.vars, ["s", "o", "unused"]);
+        assert_json_bindings(&results);
+    }
+
+    #[test]
+    fn json_results_before_head() {
+        let json = format!(
+            r#"{{ {}, "head": {{ "vars": ["s", "o", "unused"] }} }}"#,
+            JSON_BINDINGS
+        );
+        let mut results = Collect::default();
+        read_sparql_results_json(json.as_bytes(), &mut results).unwrap();
+
+        assert_eq!(results.vars, ["s", "o", "unused"]);
+        assert_json_bindings(&results);
+    }
+
+    #[test]
+    fn json_without_head() {
+        let json = format!("{{ {} }}", JSON_BINDINGS);
+        let mut results = Collect::default();
+        read_sparql_results_json(json.as_bytes(), &mut results).unwrap();
+
+        assert_eq!(results.vars, ["o", "s"]);
+        assert_json_bindings(&results);
+    }
+
+    #[test]
+    fn json_truncated() {
+        let json = format!(r#"{{ "head": {{ "vars": ["s", "o"] }}, {}"#, JSON_BINDINGS);
+        let mut results = Collect::default();
+        assert!(read_sparql_results_json(json.as_bytes(), &mut results).is_err());
+        let json = &json[..json.len() / 2];
+        assert!(read_sparql_results_json(json.as_bytes(), &mut results).is_err());
+    }
+
+    #[test]
+    fn tsv_terms() {
+        let tsv = "?s\t?o\r\n\
+            <http://example.org/a>\t\"caf\\u00E9 \\\"au\\\" lait\"@fr\r\n\
+            _:b0\t\"12\"^^<http://www.w3.org/2001/XMLSchema#int>\r\n\
+            \t1.5e3\r\n\
+            <http://example.org/b>\t-4\r\n";
+        let mut results = Collect::default();
+        read_sparql_results_tsv(tsv.as_bytes(), &mut results).unwrap();
+
+        assert_eq!(results.vars, ["s", "o"]);
+        let [first, second, third, fourth] = results.bindings.as_slice() else {
+            panic!("Expected 4 bindings, got {}", results.bindings.len());
+        };
+        assert_eq!(
+            term(&first["s"]),
+            ("uri", "http://example.org/a", None, None)
+        );
+        assert_eq!(
+            term(&first["o"]),
+            ("literal", "café \"au\" lait", Some("fr"), None)
+        );
+        assert_eq!(term(&second["s"]), ("bnode", "b0", None, None));
+        assert_eq!(
+            term(&second["o"]),
+            (
+                "literal",
+                "12",
+                None,
+                Some("http://www.w3.org/2001/XMLSchema#int")
+            )
+        );
+        assert!(!third.contains_key("s"));
+        assert_eq!(
+            term(&third["o"]),
+            ("literal", "1.5e3", None, Some(XSD_DOUBLE))
+        );
+        assert_eq!(
+            term(&fourth["o"]),
+            ("literal", "-4", None, Some(XSD_INTEGER))
+        );
+    }
+
+    #[test]
+    fn tsv_invalid_terms() {
+        let mut results = Collect::default();
+        assert!(read_sparql_results_tsv("?o\n\"open\n".as_bytes(), &mut results).is_err());
+        assert!(read_sparql_results_tsv("?o\nbare\n".as_bytes(), &mut results).is_err());
+        assert!(read_sparql_results_tsv("?o\n\"x\"y\n".as_bytes(), &mut results).is_err());
+        assert!(read_sparql_results_tsv("".as_bytes(), &mut results).is_err());
+        assert!(
+            read_sparql_results_tsv(
+                "s\t?o\n<http://example.org/a>\t1\n".as_bytes(),
+                &mut results
+            )
+            .is_err()
+        );
+        assert!(read_sparql_results_tsv("?\n1\n".as_bytes(), &mut results).is_err());
+    }
+
+    #[test]
+    fn xml_terms() {
+        let xml = r#"<?xml version="1.0"?>
+<sparql xmlns="http://www.w3.org/2005/sparql-results#">
+  <head>
+    <variable name="s"/>
+    <variable name="o"/>
+  </head>
+  <results>
+    <result>
+      <binding name="s"><uri>http://example.org/a?x=1&amp;y=2</uri></binding>
+      <binding name="o"><literal xml:lang="en">fish &amp; chips</literal></binding>
+    </result>
+    <result>
+      <binding name="s"><bnode>b0</bnode></binding>
+      <binding name="o"><literal datatype="http://www.w3.org/2001/XMLSchema#integer">42</literal></binding>
+    </result>
+    <result>
+      <binding name="o"><literal/></binding>
+    </result>
+    <result>
+      <binding name="o"><literal><![CDATA[<b>]]></literal></binding>
+    </result>
+    <result/>
+  </results>
+</sparql>"#;
+        let mut results = Collect::default();
+        read_sparql_results_xml(xml.as_bytes(), &mut results).unwrap();
+
+        assert_eq!(results.vars, ["s", "o"]);
+        let [first, second, third, fourth, fifth] = results.bindings.as_slice() else {
+            panic!("Expected 5 bindings, got {}", results.bindings.len());
+        };
+        assert_eq!(
+            term(&first["s"]),
+            ("uri", "http://example.org/a?x=1&y=2", None, None)
+        );
+        assert_eq!(
+            term(&first["o"]),
+            ("literal", "fish & chips", Some("en"), None)
+        );
+        assert_eq!(term(&second["s"]), ("bnode", "b0", None, None));
+        assert_eq!(
+            term(&second["o"]),
+            ("literal", "42", None, Some(XSD_INTEGER))
+        );
+        assert!(!third.contains_key("s"));
+        assert_eq!(term(&third["o"]), ("literal", "", None, None));
+        assert_eq!(term(&fourth["o"]), ("literal", "<b>", None, None));
+        assert!(fifth.is_empty());
+    }
+}
@@ -1,5 +1,7 @@
 use duckdb::params;
 
+use crate::sparql_result_to_duckdb::Coercion;
+
 pub fn ensure_metadata_schema(conn: &duckdb::Connection) -> anyhow::Result<()> {
     conn.prepare("CREATE SCHEMA IF NOT EXISTS tabulae")?
         .execute([])?;
@@ -7,31 +9,98 @@ pub fn ensure_metadata_schema(conn: &duckdb::Connection) -> anyhow::Result<()> {
         "CREATE TABLE IF NOT EXISTS tabulae.sparql_queries (name STRING PRIMARY KEY, query STRING NOT NULL, mtime TIMESTAMP NOT NULL)",
     )?
     .execute([])?;
+    // Added after the table was introduced, so older databases need to be migrated
+    conn.prepare("ALTER TABLE tabulae.sparql_queries ADD COLUMN IF NOT EXISTS hash STRING")?
+        .execute([])?;
+    conn.prepare(
+        "ALTER TABLE tabulae.sparql_queries ADD COLUMN IF NOT EXISTS fetched_at TIMESTAMP",
+    )?
+    .execute([])?;
+    conn.prepare(
+        "CREATE TABLE IF NOT EXISTS tabulae.type_coercions (table_name STRING NOT NULL, column_name STRING NOT NULL, datatype STRING NOT NULL, column_type STRING NOT NULL, num_values BIGINT NOT NULL)",
+    )?
+    .execute([])?;
 
     Ok(())
 }
 
-pub fn get_mtime(conn: &duckdb::Connection, name: &str) -> anyhow::Result<Option<i64>> {
-    let mut stmt = conn.prepare("SELECT mtime FROM tabulae.sparql_queries WHERE name = ?")?;
+/// What is recorded about the last build of a Layer 1 table
+pub struct StoredQuery {
+    pub query: String,
+    /// NULL for tables built before hashes were recorded
+    pub hash: Option<String>,
+    /// Time of the last successful fetch in microseconds since the Unix epoch; NULL for tables
+    /// built before fetch times were recorded
+    pub fetched_at_us: Option<i64>,
+}
+
+pub fn get_stored_query(
+    conn: &duckdb::Connection,
+    name: &str,
+) -> anyhow::Result<Option<StoredQuery>> {
+    let mut stmt = conn.prepare(
+        "SELECT query, hash, epoch_us(fetched_at) FROM tabulae.sparql_queries WHERE name = ?",
+    )?;
     let mut rows = stmt.query(params![name])?;
-    let t = if let Some(row) = rows.next()? {
-        Some(row.get(0)?)
+    let stored = if let Some(row) = rows.next()? {
+        Some(StoredQuery {
+            query: row.get(0)?,
+            hash: row.get(1)?,
+            fetched_at_us: row.get(2)?,
+        })
     } else {
         None
     };
 
-    Ok(t)
+    Ok(stored)
 }
 
-pub fn touch_mtime(
+pub fn record_query(
     conn: &duckdb::Connection,
     name: &str,
     query: &str,
     mtime: i64,
+    hash: &str,
+    fetched_at: i64,
 ) -> anyhow::Result<()> {
     conn.execute(
-        "INSERT OR REPLACE INTO tabulae.sparql_queries (name, query, mtime) VALUES (?, ?, make_timestamp(?))",
-        params![name, query, mtime],
+        "INSERT OR REPLACE INTO tabulae.sparql_queries (name, query, mtime, hash, fetched_at) VALUES (?, ?, make_timestamp(?), ?, make_timestamp(?))",
+        params![name, query, mtime, hash, fetched_at],
+    )?;
+
+    Ok(())
+}
+
+/// Replaces the values recorded as widened in the last build of a table
+pub fn record_coercions(
+    conn: &duckdb::Connection,
+    name: &str,
+    coercions: &[Coercion],
+) -> anyhow::Result<()> {
+    conn.execute(
+        "DELETE FROM tabulae.type_coercions WHERE table_name = ?",
+        params![name],
+    )?;
+    let mut stmt = conn.prepare(
+        "INSERT INTO tabulae.type_coercions (table_name, column_name, datatype, column_type, num_values) VALUES (?, ?, ?, ?, ?)",
+    )?;
+    for coercion in coercions {
+        stmt.execute(params![
+            name,
+            coercion.column,
+            coercion.datatype,
+            coercion.column_type,
+            coercion.count as i64
+        ])?;
+    }
+
+    Ok(())
+}
+
+pub fn set_hash(conn: &duckdb::Connection, name: &str, hash: &str) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE tabulae.sparql_queries SET hash = ? WHERE name = ?",
+        params![hash, name],
     )?;
 
     Ok(())
@@ -50,5 +119,9 @@ pub fn delete(conn: &duckdb::Connection, name: &str) -> anyhow::Result<()> {
         "DELETE FROM tabulae.sparql_queries WHERE name = ?",
         params![name],
     )?;
+    conn.execute(
+        "DELETE FROM tabulae.type_coercions WHERE table_name = ?",
+        params![name],
+    )?;
     Ok(())
 }